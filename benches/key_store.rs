@@ -0,0 +1,100 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use pqkd_relay::etsi_server::{Key, Share};
+use pqkd_relay::store::{FileStore, KeyStore, MemoryStore};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+const PENDING: [usize; 3] = [1_000, 10_000, 100_000];
+/// Every file store insert is a durable commit, so filling it takes longer.
+const FILE_PENDING: [usize; 2] = [1_000, 10_000];
+
+fn key(key_id: String) -> Key {
+    Key {
+        key: KEY.to_string().into(),
+        key_id,
+        extensions: Default::default(),
+    }
+}
+
+fn share() -> Share {
+    Share {
+        index: 0,
+        total: 1,
+        threshold: None,
+    }
+}
+
+/// `store` once it holds `pending` keys from 16 origins.
+fn filled<S: KeyStore>(store: S, pending: usize) -> S {
+    for i in 0..pending {
+        store
+            .add_share(
+                format!("SAE_{}", i % 16),
+                share(),
+                key(format!("key-{}", i)),
+            )
+            .expect("add share");
+    }
+    store
+}
+
+fn insert<S: KeyStore>(c: &mut Criterion, name: &str, pending: &[usize], store: impl Fn() -> S) {
+    let mut group = c.benchmark_group(format!("{}/insert", name));
+    for &pending in pending {
+        let store = filled(store(), pending);
+        let next = AtomicUsize::new(0);
+        group.bench_with_input(BenchmarkId::from_parameter(pending), &pending, |b, _| {
+            b.iter(|| {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                store
+                    .add_share("SAE_0".to_string(), share(), key(format!("new-{}", i)))
+                    .expect("add share")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn fetch<S: KeyStore>(c: &mut Criterion, name: &str, pending: &[usize], store: impl Fn() -> S) {
+    let mut group = c.benchmark_group(format!("{}/fetch", name));
+    for &pending in pending {
+        let store = filled(store(), pending);
+        let next = AtomicUsize::new(0);
+        group.bench_with_input(BenchmarkId::from_parameter(pending), &pending, |b, _| {
+            b.iter_batched(
+                || {
+                    let key_id = format!("new-{}", next.fetch_add(1, Ordering::Relaxed));
+                    store
+                        .add_share("SAE_0".to_string(), share(), key(key_id.clone()))
+                        .expect("add share");
+                    key_id
+                },
+                |key_id| store.take_key("SAE_0", &key_id).expect("take key"),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn memory_store(c: &mut Criterion) {
+    insert(c, "memory_store", &PENDING, MemoryStore::default);
+    fetch(c, "memory_store", &PENDING, MemoryStore::default);
+}
+
+fn file_store(c: &mut Criterion) {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let next = AtomicUsize::new(0);
+    let store = || {
+        let path = dir.path().join(format!(
+            "keys-{}.redb",
+            next.fetch_add(1, Ordering::Relaxed)
+        ));
+        FileStore::open(&path, None).expect("open").for_sae("Alice")
+    };
+    insert(c, "file_store", &FILE_PENDING, store);
+    fetch(c, "file_store", &FILE_PENDING, store);
+}
+
+criterion_group!(benches, memory_store, file_store);
+criterion_main!(benches);
@@ -1,9 +1,10 @@
-use clap::Parser;
+use crate::topology::Format;
+use clap::{ArgAction, Parser, Subcommand};
 use std::path::PathBuf;
 /// todo
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, disable_help_flag = true)]
 pub struct Args {
     /// Path to config file
     #[arg(short = 'c', long = "config", value_name = "CONFIG_FILE")]
@@ -11,6 +12,39 @@ pub struct Args {
     // Path to file with topologi networks pqkd
     #[arg(short = 'h', long = "hypercube", value_name = "HYPERCUBE_FILE")]
     pub hypercube_file: PathBuf,
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check the config and topology files for inconsistencies and exit
+    Validate,
+    /// Print the topology as Graphviz DOT, Mermaid or JSON and exit
+    Export {
+        #[arg(short = 'f', long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        /// Highlight the paths chosen from this SAE
+        #[arg(long, value_name = "SAE_ID", requires = "to")]
+        from: Option<String>,
+        /// Highlight the paths chosen to this SAE
+        #[arg(long, value_name = "SAE_ID", requires = "from")]
+        to: Option<String>,
+    },
+    /// Print the relay paths and SAE hop lists keys from one SAE to another
+    /// would take, without requesting any key, and exit
+    Preview {
+        #[arg(long, value_name = "SAE_ID")]
+        from: String,
+        #[arg(long, value_name = "SAE_ID")]
+        to: String,
+    },
+    /// Write a new ML-DSA-65 signing key to the `[attestation]` key file,
+    /// print its public key for the other relays' trust stores and exit
+    Keygen,
 }
 
 impl Args {
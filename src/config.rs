@@ -1,9 +1,11 @@
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
-use std::{error, fs, path::PathBuf};
+use std::time::Duration;
+use std::{error, fmt, fs, path::PathBuf};
+
+use crate::health::LinkHealth;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pqkd {
@@ -15,6 +17,8 @@ pub struct Pqkd {
     ca_cert: Option<PathBuf>,
     client_cert: Option<PathBuf>,
     client_key: Option<PathBuf>,
+    /// Serves the ETSI façade over TLS with client certificates when set.
+    tls: Option<ServerTls>,
 }
 
 impl Pqkd {
@@ -49,12 +53,79 @@ impl Pqkd {
     pub fn client_key(&self) -> &Option<PathBuf> {
         &self.client_key
     }
+
+    pub fn tls(&self) -> Option<&ServerTls> {
+        self.tls.as_ref()
+    }
+}
+
+/// TLS of an ETSI façade or of the relay endpoint. Every client must present
+/// a certificate issued by `client_ca`. Its subject common name, or else its
+/// first DNS name, identifies the calling SAE or relay, translated through
+/// `identities` when listed there.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerTls {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: PathBuf,
+    #[serde(default)]
+    identities: HashMap<String, String>,
+    /// Only agree keys with the hybrid X25519MLKEM768 group over TLS 1.3.
+    #[serde(default)]
+    post_quantum: bool,
+}
+
+impl ServerTls {
+    pub fn cert(&self) -> &std::path::Path {
+        &self.cert
+    }
+
+    pub fn key(&self) -> &std::path::Path {
+        &self.key
+    }
+
+    pub fn client_ca(&self) -> &std::path::Path {
+        &self.client_ca
+    }
+
+    pub fn post_quantum(&self) -> bool {
+        self.post_quantum
+    }
+
+    /// SAE or relay id of the client whose certificate carries `identity`.
+    pub fn peer_id<'a>(&'a self, identity: &'a str) -> &'a str {
+        self.identities
+            .get(identity)
+            .map(String::as_str)
+            .unwrap_or(identity)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     id: String,
     port: u16,
+    /// Seconds between KME status polls feeding the link-health table.
+    status_interval: Option<u64>,
+    /// File relayed keys are persisted in; kept in memory only when unset.
+    key_store: Option<PathBuf>,
+    /// Encrypts every record of `key_store` when set.
+    key_store_encryption: Option<Encryption>,
+    /// Seconds a relayed key is kept waiting for `dec_keys`.
+    key_ttl: Option<u64>,
+    /// Most relayed keys held for one local SAE.
+    max_keys: Option<usize>,
+    /// Most relayed keys from one origin SAE held for one local SAE.
+    max_keys_per_origin: Option<usize>,
+    /// Seconds an `/info_keys` message may be older or newer than the local
+    /// clock; its transfer id is remembered that long to refuse replays.
+    replay_window: Option<u64>,
+    /// Serves `/info_keys` over TLS to neighbouring relays, which present
+    /// certificates issued by `client_ca`. Also used to call them.
+    tls: Option<ServerTls>,
+    /// Signs every hop of relayed keys and checks the signatures of keys
+    /// stored here.
+    attestation: Option<AttestationKeys>,
     pqkds: Vec<Pqkd>,
 }
 
@@ -74,11 +145,114 @@ impl Config {
         self.port
     }
 
+    pub fn status_interval(&self) -> Duration {
+        Duration::from_secs(self.status_interval.unwrap_or(30))
+    }
+
+    pub fn tls(&self) -> Option<&ServerTls> {
+        self.tls.as_ref()
+    }
+
+    pub fn attestation(&self) -> Option<&AttestationKeys> {
+        self.attestation.as_ref()
+    }
+
+    pub fn key_store(&self) -> Option<&std::path::Path> {
+        self.key_store.as_deref()
+    }
+
+    pub fn key_ttl(&self) -> Duration {
+        Duration::from_secs(self.key_ttl.unwrap_or(3600))
+    }
+
+    pub fn max_keys(&self) -> usize {
+        self.max_keys.unwrap_or(100_000)
+    }
+
+    pub fn max_keys_per_origin(&self) -> usize {
+        self.max_keys_per_origin.unwrap_or(10_000)
+    }
+
+    pub fn replay_window(&self) -> Duration {
+        Duration::from_secs(self.replay_window.unwrap_or(300))
+    }
+
+    pub fn key_store_encryption(&self) -> Option<&Encryption> {
+        self.key_store_encryption.as_ref()
+    }
+
     pub fn pqkds(&self) -> &Vec<Pqkd> {
         &self.pqkds
     }
 }
 
+/// ML-DSA-65 signing key of this relay, a PKCS#8 PEM file, and the public
+/// keys of the other relays by relay id, files holding the base64 of the raw
+/// key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttestationKeys {
+    key: PathBuf,
+    #[serde(default)]
+    trust: HashMap<String, PathBuf>,
+}
+
+impl AttestationKeys {
+    pub fn key(&self) -> &std::path::Path {
+        &self.key
+    }
+
+    pub fn trust(&self) -> &HashMap<String, PathBuf> {
+        &self.trust
+    }
+}
+
+/// Where a key-encryption key is read from: a file or an environment
+/// variable holding the base64 of 32 bytes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KekSource {
+    file: Option<PathBuf>,
+    env: Option<String>,
+}
+
+impl KekSource {
+    pub fn file(&self) -> Option<&std::path::Path> {
+        self.file.as_deref()
+    }
+
+    pub fn env(&self) -> Option<&str> {
+        self.env.as_deref()
+    }
+}
+
+impl fmt::Display for KekSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, &self.env) {
+            (Some(file), _) => write!(f, "file {}", file.display()),
+            (None, Some(env)) => write!(f, "environment variable {}", env),
+            (None, None) => write!(f, "empty key source"),
+        }
+    }
+}
+
+/// At-rest encryption of the key store. Records sealed under one of the
+/// `previous` keys are re-encrypted under `kek` when the store is opened.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Encryption {
+    kek: KekSource,
+    #[serde(default)]
+    previous: Vec<KekSource>,
+}
+
+impl Encryption {
+    pub fn kek(&self) -> &KekSource {
+        &self.kek
+    }
+
+    pub fn previous(&self) -> &[KekSource] {
+        &self.previous
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Relay {
     id: String,
@@ -99,6 +273,12 @@ impl Relay {
 pub struct Connection {
     first: String,
     second: String,
+    /// Static cost of the link.
+    cost: Option<f64>,
+    /// Nominal secret-key rate in bit/s.
+    key_rate: Option<f64>,
+    /// Latency in milliseconds.
+    latency: Option<f64>,
 }
 
 impl Connection {
@@ -111,10 +291,80 @@ impl Connection {
     }
 }
 
+/// Coefficients of the link cost minimised by the path search:
+/// `hop + cost * c + latency * l + key_rate / r + depletion * d` for a
+/// connection with static cost `c`, latency `l`, key rate `r` and a polled
+/// key buffer that is `d` used up. Unset connection fields add nothing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Weights {
+    hop: f64,
+    cost: f64,
+    latency: f64,
+    key_rate: f64,
+    depletion: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            hop: 1.0,
+            cost: 1.0,
+            latency: 0.0,
+            key_rate: 0.0,
+            depletion: 0.0,
+        }
+    }
+}
+
+impl Weights {
+    /// Cost of relaying over `con`, `None` when the link is down or out of keys.
+    pub fn link_cost(&self, con: &Connection, health: &LinkHealth) -> Option<f64> {
+        let depletion = match health.get(&con.first, &con.second) {
+            Some(state) if !state.is_usable() => return None,
+            Some(state) => self.depletion * state.depletion(),
+            None => 0.0,
+        };
+        let rate = match con.key_rate {
+            Some(r) if r > 0.0 => self.key_rate / r,
+            _ => 0.0,
+        };
+        Some(
+            self.hop
+                + self.cost * con.cost.unwrap_or(0.0)
+                + self.latency * con.latency.unwrap_or(0.0)
+                + rate
+                + depletion,
+        )
+    }
+}
+
+/// Relay adjacency with the cost of the cheapest link to each neighbour.
+pub type Graph = HashMap<String, Vec<(String, f64)>>;
+
+/// How alternative relay paths are selected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMode {
+    /// The `n` cheapest simple paths; they may share intermediate relays.
+    #[default]
+    Shortest,
+    /// Up to `n` paths with no intermediate relay in common.
+    Disjoint,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Hypercube {
     dimension: usize,
     n: usize,
+    #[serde(default)]
+    paths: PathMode,
+    #[serde(default)]
+    threshold: Option<usize>,
+    #[serde(default)]
+    retries: usize,
+    #[serde(default)]
+    weights: Weights,
     relay: Vec<Relay>,
     connection: Vec<Connection>,
 }
@@ -135,6 +385,16 @@ impl Hypercube {
         self.n
     }
 
+    pub fn path_mode(&self) -> PathMode {
+        self.paths
+    }
+
+    /// Number of Shamir shares needed to rebuild a key, `None` when every
+    /// path has to deliver (XOR sharing).
+    pub fn threshold(&self) -> Option<usize> {
+        self.threshold
+    }
+
     pub fn relay(&self) -> &Vec<Relay> {
         &self.relay
     }
@@ -152,17 +412,308 @@ impl Hypercube {
         }
         None
     }
+
+    /// Relay adjacency built from the `[[relay]]` and `[[connection]]` entries.
+    /// Two relays are neighbours when a connection links an SAE hosted on one
+    /// of them to an SAE hosted on the other; several such connections count
+    /// as the cheapest one. Links that `health` reports unusable are left out.
+    pub fn graph(&self, health: &LinkHealth) -> Graph {
+        let mut graph: Graph = self
+            .relay
+            .iter()
+            .map(|r| (r.id.clone(), Vec::new()))
+            .collect();
+
+        for con in self.connection.iter() {
+            let Some(cost) = self.weights.link_cost(con, health) else {
+                continue;
+            };
+            for a in self.relay.iter().filter(|r| r.pqkds().contains(&con.first)) {
+                for b in self
+                    .relay
+                    .iter()
+                    .filter(|r| r.pqkds().contains(&con.second))
+                {
+                    if a.id == b.id {
+                        continue;
+                    }
+                    for (x, y) in [(&a.id, &b.id), (&b.id, &a.id)] {
+                        let neighbors = graph.entry(x.clone()).or_default();
+                        match neighbors.iter_mut().find(|(n, _)| n == y) {
+                            Some((_, c)) => *c = c.min(cost),
+                            None => neighbors.push((y.clone(), cost)),
+                        }
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    /// SAE pair `(on from, on to)` of the cheapest usable connection linking
+    /// two relays.
+    pub fn link(&self, from: &str, to: &str, health: &LinkHealth) -> Option<(&str, &str)> {
+        let from = self.relay.iter().find(|r| r.id == from)?;
+        let to = self.relay.iter().find(|r| r.id == to)?;
+        self.connection
+            .iter()
+            .filter_map(|con| {
+                let pair = if from.pqkds().contains(&con.first) && to.pqkds().contains(&con.second)
+                {
+                    (con.first(), con.second())
+                } else if from.pqkds().contains(&con.second) && to.pqkds().contains(&con.first) {
+                    (con.second(), con.first())
+                } else {
+                    return None;
+                };
+                Some((self.weights.link_cost(con, health)?, pair))
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, pair)| pair)
+    }
+
+    /// SAE hop list that relays keys along `relay_path` from `source` to
+    /// `target`: both SAEs of every link, framed by the two end SAEs.
+    pub fn sae_path(
+        &self,
+        relay_path: &[String],
+        source: &str,
+        target: &str,
+        health: &LinkHealth,
+    ) -> Option<Vec<String>> {
+        let mut v: Vec<String> = Vec::new();
+
+        for hop in relay_path.windows(2) {
+            let (from, to) = self.link(&hop[0], &hop[1], health)?;
+            v.push(String::from(from));
+            v.push(String::from(to));
+        }
+        if v.last()? != target {
+            v.push(String::from(target));
+        }
+        if v.first()? != source {
+            v.insert(0, String::from(source));
+        }
+        Some(v)
+    }
+
+    /// Up to `n` relay paths from `start` to `end` selected according to `paths`.
+    pub fn find_paths(&self, start: &str, end: &str, health: &LinkHealth) -> Vec<Vec<String>> {
+        let graph = self.graph(health);
+        match self.paths {
+            PathMode::Shortest => find_n_shortest_paths(&graph, start, end, self.n),
+            PathMode::Disjoint => find_n_disjoint_paths(&graph, start, end, self.n),
+        }
+    }
+
+    /// Up to `retries` next-cheapest paths that are not already in `used`, in
+    /// order of increasing cost. A spare replaces a failed share while the
+    /// others are still in flight, so in disjoint mode the spares cross no
+    /// intermediate relay of `used` nor of each other.
+    pub fn find_spare_paths(
+        &self,
+        start: &str,
+        end: &str,
+        health: &LinkHealth,
+        used: &[Vec<String>],
+    ) -> Vec<Vec<String>> {
+        if self.retries == 0 {
+            return Vec::new();
+        }
+        let mut graph = self.graph(health);
+        let spares = match self.paths {
+            PathMode::Shortest => {
+                find_n_shortest_paths(&graph, start, end, used.len() + self.retries)
+            }
+            PathMode::Disjoint => {
+                let taken: Vec<&String> = used
+                    .iter()
+                    .flat_map(|p| p.iter().take(p.len().saturating_sub(1)).skip(1))
+                    .collect();
+                graph.retain(|relay, _| !taken.contains(&relay));
+                for neighbors in graph.values_mut() {
+                    neighbors.retain(|(relay, _)| !taken.contains(&relay));
+                }
+                find_n_disjoint_paths(&graph, start, end, self.retries)
+            }
+        };
+        spares
+            .into_iter()
+            .filter(|p| !used.contains(p))
+            .take(self.retries)
+            .collect()
+    }
+}
+
+/// Every inconsistency found between `config.toml` and `hypercube.toml`.
+pub struct ValidationError(pub Vec<String>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} configuration problem(s):", self.0.len())?;
+        for problem in self.0.iter() {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl error::Error for ValidationError {}
+
+/// Checks that the relay configuration and the topology agree.
+pub fn validate(config: &Config, hypercube: &Hypercube) -> Result<(), ValidationError> {
+    let mut problems = Vec::new();
+
+    if hypercube.n == 0 {
+        problems.push("`n` must be at least 1".to_string());
+    }
+    if let Some(k) = hypercube.threshold {
+        if k == 0 || k > hypercube.n {
+            problems.push(format!(
+                "`threshold` {} must be between 1 and `n` ({})",
+                k, hypercube.n
+            ));
+        }
+        if hypercube.n > 255 {
+            problems.push(format!(
+                "`n` {} exceeds 255, the Shamir share limit",
+                hypercube.n
+            ));
+        }
+    }
+
+    for (i, relay) in hypercube.relay.iter().enumerate() {
+        if hypercube.relay[..i].iter().any(|r| r.id == relay.id) {
+            problems.push(format!("relay id \"{}\" is declared twice", relay.id));
+        }
+    }
+
+    // Negative costs would give the path searches negative cycles.
+    let weights = &hypercube.weights;
+    for (name, value) in [
+        ("hop", weights.hop),
+        ("cost", weights.cost),
+        ("latency", weights.latency),
+        ("key_rate", weights.key_rate),
+        ("depletion", weights.depletion),
+    ] {
+        if !(value.is_finite() && value >= 0.0) {
+            problems.push(format!(
+                "weight `{}` {} must be a non-negative number",
+                name, value
+            ));
+        }
+    }
+
+    for con in hypercube.connection.iter() {
+        for (name, value) in [
+            ("cost", con.cost),
+            ("key_rate", con.key_rate),
+            ("latency", con.latency),
+        ] {
+            if let Some(value) = value.filter(|v| !(v.is_finite() && *v >= 0.0)) {
+                problems.push(format!(
+                    "connection {} - {}: `{}` {} must be a non-negative number",
+                    con.first, con.second, name, value
+                ));
+            }
+        }
+        for sae_id in [&con.first, &con.second] {
+            if hypercube.find_relay(sae_id).is_none() {
+                problems.push(format!(
+                    "connection {} - {}: SAE \"{}\" does not belong to any relay",
+                    con.first, con.second, sae_id
+                ));
+            }
+        }
+        if con.first == con.second {
+            problems.push(format!(
+                "connection {} - {} is a loop",
+                con.first, con.second
+            ));
+        }
+    }
+
+    let local = hypercube.relay.iter().find(|r| r.id == config.id);
+    if local.is_none() {
+        problems.push(format!(
+            "relay id \"{}\" from the config is missing in the topology",
+            config.id
+        ));
+    }
+
+    for (i, pqkd) in config.pqkds.iter().enumerate() {
+        if let Some(local) = local {
+            if !local.pqkds.contains(&pqkd.sae_id) {
+                problems.push(format!(
+                    "SAE \"{}\" is not listed on relay \"{}\"",
+                    pqkd.sae_id, config.id
+                ));
+            }
+        }
+        let linked = hypercube.connection.iter().any(|con| {
+            (con.first == pqkd.sae_id && con.second == pqkd.remote_sae_id)
+                || (con.second == pqkd.sae_id && con.first == pqkd.remote_sae_id)
+        });
+        if !linked {
+            problems.push(format!(
+                "no connection links SAE \"{}\" to its remote_sae_id \"{}\"",
+                pqkd.sae_id, pqkd.remote_sae_id
+            ));
+        }
+        if pqkd.port == config.port || config.pqkds[..i].iter().any(|p| p.port == pqkd.port) {
+            problems.push(format!(
+                "port {} of SAE \"{}\" is already in use",
+                pqkd.port, pqkd.sae_id
+            ));
+        }
+    }
+
+    if config.status_interval == Some(0) {
+        problems.push("`status_interval` must be at least 1 second".to_string());
+    }
+
+    if let Some(encryption) = &config.key_store_encryption {
+        if config.key_store.is_none() {
+            problems.push("key_store_encryption is set but key_store is not".to_string());
+        }
+        for source in std::iter::once(&encryption.kek).chain(encryption.previous.iter()) {
+            if source.file.is_some() == source.env.is_some() {
+                problems
+                    .push("every key-encryption key needs exactly one of file or env".to_string());
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError(problems))
+    }
 }
 
-#[derive(Eq, PartialEq)]
 pub struct Path {
-    cost: usize,
+    cost: f64,
     nodes: Vec<String>,
 }
 
+impl PartialEq for Path {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Path {}
+
 impl Ord for Path {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.cost.cmp(&self.cost)
+        other.cost.total_cmp(&self.cost)
     }
 }
 
@@ -172,42 +723,44 @@ impl PartialOrd for Path {
     }
 }
 
+#[deprecated(note = "relay ids are no longer hypercube addresses")]
 pub fn hamming_distance(a: &str, b: &str) -> usize {
     a.chars().zip(b.chars()).filter(|(c1, c2)| c1 != c2).count()
 }
 
+#[deprecated(
+    note = "routes follow the relays and connections of the hypercube file, see `Hypercube::graph`"
+)]
+#[allow(deprecated)]
 pub fn build_hypercube(dim: usize) -> HashMap<String, Vec<String>> {
     let nodes: Vec<String> = (0..1 << dim)
         .map(|i| format!("{:0width$b}", i, width = dim))
         .collect();
 
     let mut graph = HashMap::new();
-    for (a, b) in nodes.iter().tuple_combinations() {
-        if hamming_distance(a, b) == 1 {
-            graph
-                .entry(a.clone())
-                .or_insert_with(Vec::new)
-                .push(b.clone());
-            graph
-                .entry(b.clone())
-                .or_insert_with(Vec::new)
-                .push(a.clone());
+    for (i, a) in nodes.iter().enumerate() {
+        for b in &nodes[i + 1..] {
+            if hamming_distance(a, b) == 1 {
+                graph
+                    .entry(a.clone())
+                    .or_insert_with(Vec::new)
+                    .push(b.clone());
+                graph
+                    .entry(b.clone())
+                    .or_insert_with(Vec::new)
+                    .push(a.clone());
+            }
         }
     }
     graph
 }
 
-pub fn find_n_shortest_paths(
-    graph: &HashMap<String, Vec<String>>,
-    start: &str,
-    end: &str,
-    n: usize,
-) -> Vec<Vec<String>> {
+pub fn find_n_shortest_paths(graph: &Graph, start: &str, end: &str, n: usize) -> Vec<Vec<String>> {
     let mut heap = BinaryHeap::new();
     let mut paths = Vec::new();
 
     heap.push(Path {
-        cost: 0,
+        cost: 0.0,
         nodes: vec![start.to_string()],
     });
 
@@ -223,12 +776,12 @@ pub fn find_n_shortest_paths(
         }
 
         if let Some(neighbors) = graph.get(current) {
-            for neighbor in neighbors {
+            for (neighbor, link_cost) in neighbors {
                 if !nodes.contains(neighbor) {
                     let mut new_path = nodes.clone();
                     new_path.push(neighbor.clone());
                     heap.push(Path {
-                        cost: cost + 1,
+                        cost: cost + link_cost,
                         nodes: new_path,
                     });
                 }
@@ -239,19 +792,232 @@ pub fn find_n_shortest_paths(
     paths
 }
 
+fn path_cost(graph: &Graph, path: &[String]) -> f64 {
+    path.windows(2)
+        .filter_map(|hop| {
+            graph
+                .get(&hop[0])?
+                .iter()
+                .find(|(n, _)| n == &hop[1])
+                .map(|(_, c)| *c)
+        })
+        .sum()
+}
+
+struct FlowEdge {
+    to: usize,
+    cap: usize,
+    cost: f64,
+}
+
+/// Residual network used by [`find_n_disjoint_paths`]. Edge `i ^ 1` is the
+/// reverse of edge `i`.
+struct FlowNetwork {
+    edges: Vec<FlowEdge>,
+    adjacency: Vec<Vec<usize>>,
+}
+
+impl FlowNetwork {
+    fn new(size: usize) -> Self {
+        Self {
+            edges: Vec::new(),
+            adjacency: vec![Vec::new(); size],
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, cap: usize, cost: f64) {
+        self.adjacency[from].push(self.edges.len());
+        self.edges.push(FlowEdge { to, cap, cost });
+        self.adjacency[to].push(self.edges.len());
+        self.edges.push(FlowEdge {
+            to: from,
+            cap: 0,
+            cost: -cost,
+        });
+    }
+
+    /// Pushes one unit of flow along the cheapest residual path (Bellman-Ford,
+    /// residual costs may be negative). Returns `false` when `sink` is unreachable.
+    fn augment(&mut self, source: usize, sink: usize) -> bool {
+        let size = self.adjacency.len();
+        let mut dist = vec![f64::INFINITY; size];
+        let mut prev: Vec<Option<usize>> = vec![None; size];
+        dist[source] = 0.0;
+
+        for _ in 0..size {
+            let mut changed = false;
+            for u in 0..size {
+                if dist[u] == f64::INFINITY {
+                    continue;
+                }
+                for &e in &self.adjacency[u] {
+                    let edge = &self.edges[e];
+                    // The epsilon keeps rounding from creating negative cycles.
+                    if edge.cap > 0 && dist[u] + edge.cost < dist[edge.to] - 1e-9 {
+                        dist[edge.to] = dist[u] + edge.cost;
+                        prev[edge.to] = Some(e);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        if dist[sink] == f64::INFINITY {
+            return false;
+        }
+
+        let mut v = sink;
+        while let Some(e) = prev[v] {
+            self.edges[e].cap -= 1;
+            self.edges[e ^ 1].cap += 1;
+            v = self.edges[e ^ 1].to;
+        }
+        true
+    }
+}
+
+/// Up to `n` node-disjoint paths from `start` to `end` with the smallest total
+/// cost (Suurballe/Bhandari as a min-cost flow). Every relay is split
+/// into an `in` and an `out` node joined by a unit-capacity edge, so no two
+/// paths can cross the same intermediate relay.
+pub fn find_n_disjoint_paths(graph: &Graph, start: &str, end: &str, n: usize) -> Vec<Vec<String>> {
+    if n == 0 {
+        return Vec::new();
+    }
+    if start == end {
+        return vec![vec![start.to_string()]];
+    }
+
+    let mut names: Vec<&String> = graph
+        .keys()
+        .chain(graph.values().flatten().map(|(n, _)| n))
+        .collect();
+    names.sort();
+    names.dedup();
+    let index: HashMap<&str, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.as_str(), i))
+        .collect();
+
+    let (Some(&s), Some(&t)) = (index.get(start), index.get(end)) else {
+        return Vec::new();
+    };
+
+    // Node `i` becomes `2 * i` (in) and `2 * i + 1` (out).
+    let mut network = FlowNetwork::new(names.len() * 2);
+    for (i, name) in names.iter().enumerate() {
+        let cap = if i == s || i == t { n } else { 1 };
+        network.add_edge(2 * i, 2 * i + 1, cap, 0.0);
+        if let Some(neighbors) = graph.get(*name) {
+            for (neighbor, cost) in neighbors {
+                network.add_edge(2 * i + 1, 2 * index[neighbor.as_str()], 1, *cost);
+            }
+        }
+    }
+
+    let mut flow = 0;
+    while flow < n && network.augment(2 * s + 1, 2 * t) {
+        flow += 1;
+    }
+
+    let mut paths = Vec::new();
+    for _ in 0..flow {
+        let mut nodes = vec![names[s].clone()];
+        let mut current = s;
+        while current != t {
+            let used = network.adjacency[2 * current + 1]
+                .iter()
+                .copied()
+                .find(|&e| {
+                    e.is_multiple_of(2)
+                        && network.edges[e].cap == 0
+                        && network.edges[e].to.is_multiple_of(2)
+                });
+            let Some(e) = used else {
+                break;
+            };
+            // Consume the edge so the next walk picks a different path.
+            network.edges[e].cap = 1;
+            current = network.edges[e].to / 2;
+            nodes.push(names[current].clone());
+        }
+        if current == t {
+            paths.push(nodes);
+        }
+    }
+    paths.sort_by(|a, b| path_cost(graph, a).total_cmp(&path_cost(graph, b)));
+    paths
+}
+
 #[cfg(test)]
 mod tests {
+    #[allow(deprecated)]
+    use super::{build_hypercube, hamming_distance};
     use super::{
-        build_hypercube, find_n_shortest_paths, hamming_distance, Connection, Hypercube, Relay,
+        find_n_disjoint_paths, find_n_shortest_paths, validate, Config, Connection, Graph,
+        Hypercube, PathMode, Relay, Weights,
     };
+    use crate::health::{LinkHealth, LinkState};
+
+    fn square_hypercube() -> Hypercube {
+        let toml = r#"
+dimension = 2
+n = 2
+
+[[relay]]
+id = "00"
+pqkds = ["A0", "A1"]
+
+[[relay]]
+id = "01"
+pqkds = ["B0", "B1"]
+
+[[relay]]
+id = "10"
+pqkds = ["C0", "C1"]
+
+[[relay]]
+id = "11"
+pqkds = ["D0", "D1"]
+
+[[connection]]
+first = "A0"
+second = "B0"
+
+[[connection]]
+first = "A1"
+second = "C0"
+
+[[connection]]
+first = "D0"
+second = "B1"
+
+[[connection]]
+first = "C1"
+second = "D1"
+"#;
+        toml::from_str(toml).expect("valid hypercube")
+    }
+
+    fn neighbors<'a>(graph: &'a Graph, relay: &str) -> Vec<&'a str> {
+        let mut neighbors: Vec<&str> = graph[relay].iter().map(|(n, _)| n.as_str()).collect();
+        neighbors.sort();
+        neighbors
+    }
 
     #[test]
+    #[allow(deprecated)]
     fn hamming_distance_counts_different_bits() {
         assert_eq!(hamming_distance("1010", "1111"), 2);
         assert_eq!(hamming_distance("000", "000"), 0);
     }
 
     #[test]
+    #[allow(deprecated)]
     fn build_hypercube_for_dim_2_has_expected_neighbors() {
         let graph = build_hypercube(2);
 
@@ -264,9 +1030,52 @@ mod tests {
         assert!(graph["10"].contains(&"11".to_string()));
     }
 
+    #[test]
+    fn graph_links_relays_through_connections() {
+        let graph = square_hypercube().graph(&LinkHealth::default());
+
+        assert_eq!(graph.len(), 4);
+        assert_eq!(neighbors(&graph, "00"), vec!["01", "10"]);
+        assert_eq!(neighbors(&graph, "01"), vec!["00", "11"]);
+        assert_eq!(neighbors(&graph, "10"), vec!["00", "11"]);
+        assert!(graph["00"].iter().all(|(_, cost)| *cost == 1.0));
+    }
+
+    #[test]
+    fn graph_ignores_relays_missing_from_topology() {
+        let mut hypercube = square_hypercube();
+        hypercube.relay.retain(|r| r.id != "01");
+        let graph = hypercube.graph(&LinkHealth::default());
+
+        assert_eq!(graph.len(), 3);
+        assert_eq!(neighbors(&graph, "00"), vec!["10"]);
+        assert!(!graph.contains_key("01"));
+
+        let paths = find_n_shortest_paths(&graph, "00", "11", 2);
+        assert_eq!(
+            paths,
+            vec![vec!["00".to_string(), "10".to_string(), "11".to_string()]]
+        );
+    }
+
+    #[test]
+    fn link_returns_sae_pair_oriented_from_first_relay() {
+        let hypercube = square_hypercube();
+
+        assert_eq!(
+            hypercube.link("00", "01", &LinkHealth::default()),
+            Some(("A0", "B0"))
+        );
+        assert_eq!(
+            hypercube.link("11", "01", &LinkHealth::default()),
+            Some(("D0", "B1"))
+        );
+        assert_eq!(hypercube.link("00", "11", &LinkHealth::default()), None);
+    }
+
     #[test]
     fn find_n_shortest_paths_returns_two_shortest_routes_in_dim_2() {
-        let graph = build_hypercube(2);
+        let graph = square_hypercube().graph(&LinkHealth::default());
         let paths = find_n_shortest_paths(&graph, "00", "11", 2);
 
         assert_eq!(paths.len(), 2);
@@ -285,11 +1094,476 @@ mod tests {
         }));
     }
 
+    fn graph_from_edges(edges: &[(&str, &str)]) -> Graph {
+        let mut graph = Graph::new();
+        for (a, b) in edges {
+            graph
+                .entry(a.to_string())
+                .or_default()
+                .push((b.to_string(), 1.0));
+            graph
+                .entry(b.to_string())
+                .or_default()
+                .push((a.to_string(), 1.0));
+        }
+        graph
+    }
+
+    fn path(nodes: &[&str]) -> Vec<String> {
+        nodes.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn find_n_disjoint_paths_avoids_shared_intermediate_relays() {
+        // S-A-X-T, S-B-X-T and S-A-C-T all have three hops, but only
+        // S-A-C-T and S-B-X-T are node-disjoint.
+        let graph = graph_from_edges(&[
+            ("S", "A"),
+            ("S", "B"),
+            ("A", "X"),
+            ("B", "X"),
+            ("X", "T"),
+            ("A", "C"),
+            ("C", "T"),
+        ]);
+
+        let mut paths = find_n_disjoint_paths(&graph, "S", "T", 2);
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            vec![path(&["S", "A", "C", "T"]), path(&["S", "B", "X", "T"])]
+        );
+    }
+
+    #[test]
+    fn find_n_disjoint_paths_reroutes_around_greedy_shortest_path() {
+        // The single shortest path S-A-B-T blocks both disjoint routes;
+        // the flow has to undo it to find S-A-D-T and S-C-B-T.
+        let graph = graph_from_edges(&[
+            ("S", "A"),
+            ("A", "B"),
+            ("B", "T"),
+            ("S", "C"),
+            ("C", "E"),
+            ("E", "B"),
+            ("A", "D"),
+            ("D", "F"),
+            ("F", "T"),
+        ]);
+
+        let mut paths = find_n_disjoint_paths(&graph, "S", "T", 2);
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            vec![
+                path(&["S", "A", "D", "F", "T"]),
+                path(&["S", "C", "E", "B", "T"])
+            ]
+        );
+    }
+
+    #[test]
+    fn find_n_disjoint_paths_returns_fewer_when_topology_is_a_chain() {
+        let graph = graph_from_edges(&[("S", "A"), ("A", "T")]);
+
+        let paths = find_n_disjoint_paths(&graph, "S", "T", 3);
+
+        assert_eq!(paths, vec![path(&["S", "A", "T"])]);
+    }
+
+    #[test]
+    fn find_paths_uses_mode_from_hypercube_file() {
+        let hypercube = square_hypercube();
+        assert_eq!(hypercube.path_mode(), PathMode::Shortest);
+
+        let toml = r#"
+dimension = 1
+n = 2
+paths = "disjoint"
+relay = []
+connection = []
+"#;
+        let parsed: Hypercube = toml::from_str(toml).expect("valid hypercube");
+        assert_eq!(parsed.path_mode(), PathMode::Disjoint);
+
+        let mut hypercube = hypercube;
+        hypercube.paths = PathMode::Disjoint;
+        let paths = hypercube.find_paths("00", "11", &LinkHealth::default());
+        assert_eq!(paths.len(), 2);
+        assert_ne!(paths[0][1], paths[1][1]);
+    }
+
+    fn weighted_square(weights: &str) -> Hypercube {
+        let toml = format!(
+            r#"
+dimension = 2
+n = 1
+{weights}
+
+[[relay]]
+id = "00"
+pqkds = ["A0", "A1"]
+
+[[relay]]
+id = "01"
+pqkds = ["B0", "B1"]
+
+[[relay]]
+id = "10"
+pqkds = ["C0", "C1"]
+
+[[relay]]
+id = "11"
+pqkds = ["D0", "D1"]
+
+[[connection]]
+first = "A0"
+second = "B0"
+cost = 5.0
+key_rate = 1000.0
+
+[[connection]]
+first = "A1"
+second = "C0"
+latency = 40.0
+key_rate = 100000.0
+
+[[connection]]
+first = "D0"
+second = "B1"
+
+[[connection]]
+first = "C1"
+second = "D1"
+"#
+        );
+        toml::from_str(&toml).expect("valid hypercube")
+    }
+
+    #[test]
+    fn weights_default_to_hop_count_plus_static_cost() {
+        let hypercube = weighted_square("");
+        assert_eq!(hypercube.weights, Weights::default());
+
+        let graph = hypercube.graph(&LinkHealth::default());
+        let cost = |a: &str, b: &str| {
+            graph[a]
+                .iter()
+                .find(|(n, _)| n == b)
+                .map(|(_, c)| *c)
+                .expect("linked")
+        };
+        assert_eq!(cost("00", "01"), 6.0);
+        assert_eq!(cost("00", "10"), 1.0);
+
+        assert_eq!(
+            hypercube.find_paths("00", "11", &LinkHealth::default()),
+            vec![vec!["00".to_string(), "10".to_string(), "11".to_string()]]
+        );
+    }
+
+    #[test]
+    fn find_paths_minimises_configured_cost_function() {
+        // Latency dominates: the slow 00-10 link is avoided despite the
+        // static cost on 00-01.
+        let hypercube = weighted_square("[weights]\nlatency = 1.0");
+        assert_eq!(
+            hypercube.find_paths("00", "11", &LinkHealth::default()),
+            vec![vec!["00".to_string(), "01".to_string(), "11".to_string()]]
+        );
+
+        // Key rate dominates: the 100 kbit/s link wins over the 1 kbit/s one.
+        let hypercube = weighted_square("[weights]\ncost = 0.0\nkey_rate = 100000.0");
+        assert_eq!(
+            hypercube.find_paths("00", "11", &LinkHealth::default()),
+            vec![vec!["00".to_string(), "10".to_string(), "11".to_string()]]
+        );
+    }
+
+    #[test]
+    fn graph_avoids_links_reported_down_or_empty() {
+        let hypercube = square_hypercube();
+        let health = LinkHealth::default();
+        health.update("A1", "C0", LinkState::Down);
+
+        let graph = hypercube.graph(&health);
+        assert_eq!(neighbors(&graph, "00"), vec!["01"]);
+        assert_eq!(hypercube.link("00", "10", &health), None);
+        assert_eq!(
+            hypercube.find_paths("00", "11", &health),
+            vec![vec!["00".to_string(), "01".to_string(), "11".to_string()]]
+        );
+
+        health.update(
+            "A1",
+            "C0",
+            LinkState::Up {
+                stored_key_count: 10,
+                max_key_count: 100,
+            },
+        );
+        health.update(
+            "B0",
+            "A0",
+            LinkState::Up {
+                stored_key_count: 0,
+                max_key_count: 100,
+            },
+        );
+        assert_eq!(
+            hypercube.find_paths("00", "11", &health),
+            vec![vec!["00".to_string(), "10".to_string(), "11".to_string()]]
+        );
+    }
+
+    #[test]
+    fn depletion_weight_prefers_fuller_key_buffers() {
+        let hypercube = weighted_square("[weights]\ncost = 0.0\ndepletion = 10.0");
+        let health = LinkHealth::default();
+        health.update(
+            "A1",
+            "C0",
+            LinkState::Up {
+                stored_key_count: 10,
+                max_key_count: 100,
+            },
+        );
+
+        assert_eq!(
+            hypercube.find_paths("00", "11", &health),
+            vec![vec!["00".to_string(), "01".to_string(), "11".to_string()]]
+        );
+    }
+
+    #[test]
+    fn link_prefers_cheapest_connection_between_relays() {
+        let toml = r#"
+dimension = 1
+n = 1
+
+[[relay]]
+id = "0"
+pqkds = ["A0", "A1"]
+
+[[relay]]
+id = "1"
+pqkds = ["B0", "B1"]
+
+[[connection]]
+first = "A0"
+second = "B0"
+cost = 3.0
+
+[[connection]]
+first = "B1"
+second = "A1"
+"#;
+        let hypercube: Hypercube = toml::from_str(toml).expect("valid hypercube");
+
+        assert_eq!(
+            hypercube.link("0", "1", &LinkHealth::default()),
+            Some(("A1", "B1"))
+        );
+        assert_eq!(
+            hypercube.graph(&LinkHealth::default())["0"],
+            vec![("1".to_string(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn sae_path_frames_links_with_end_saes() {
+        let hypercube = square_hypercube();
+        let health = LinkHealth::default();
+        let relays = vec!["00".to_string(), "01".to_string(), "11".to_string()];
+
+        assert_eq!(
+            hypercube.sae_path(&relays, "A1", "D1", &health),
+            Some(path(&["A1", "A0", "B0", "B1", "D0", "D1"]))
+        );
+        assert_eq!(
+            hypercube.sae_path(&relays, "A0", "D0", &health),
+            Some(path(&["A0", "B0", "B1", "D0"]))
+        );
+        assert_eq!(hypercube.sae_path(&relays[..1], "A0", "A1", &health), None);
+    }
+
+    #[test]
+    fn find_spare_paths_skips_used_paths_and_respects_budget() {
+        let mut hypercube = square_hypercube();
+        let health = LinkHealth::default();
+        let used = vec![vec!["00".to_string(), "01".to_string(), "11".to_string()]];
+
+        assert!(hypercube
+            .find_spare_paths("00", "11", &health, &used)
+            .is_empty());
+
+        hypercube.retries = 3;
+        assert_eq!(
+            hypercube.find_spare_paths("00", "11", &health, &used),
+            vec![vec!["00".to_string(), "10".to_string(), "11".to_string()]]
+        );
+    }
+
+    #[test]
+    fn disjoint_spare_paths_avoid_relays_of_live_paths() {
+        let toml = r#"
+dimension = 2
+n = 1
+retries = 1
+
+[[relay]]
+id = "00"
+pqkds = ["S0", "S1"]
+
+[[relay]]
+id = "01"
+pqkds = ["A0", "A1", "A2"]
+
+[[relay]]
+id = "10"
+pqkds = ["B0", "B1", "B2"]
+
+[[relay]]
+id = "11"
+pqkds = ["T0", "T1"]
+
+[[connection]]
+first = "S0"
+second = "A0"
+
+[[connection]]
+first = "A1"
+second = "T0"
+
+[[connection]]
+first = "S1"
+second = "B0"
+
+[[connection]]
+first = "B1"
+second = "A2"
+
+[[connection]]
+first = "B2"
+second = "T1"
+cost = 5.0
+"#;
+        let mut hypercube: Hypercube = toml::from_str(toml).expect("valid hypercube");
+        let health = LinkHealth::default();
+        let used = vec![vec!["00".to_string(), "01".to_string(), "11".to_string()]];
+
+        // The cheapest spare crosses relay 01, which still carries a share.
+        assert_eq!(
+            hypercube.find_spare_paths("00", "11", &health, &used),
+            vec![vec![
+                "00".to_string(),
+                "10".to_string(),
+                "01".to_string(),
+                "11".to_string()
+            ]]
+        );
+
+        hypercube.paths = PathMode::Disjoint;
+        assert_eq!(
+            hypercube.find_spare_paths("00", "11", &health, &used),
+            vec![vec!["00".to_string(), "10".to_string(), "11".to_string()]]
+        );
+    }
+
+    fn square_config() -> Config {
+        let toml = r#"
+id = "00"
+port = 4000
+
+[[pqkds]]
+port = 3000
+sae_id = "A0"
+remote_sae_id = "B0"
+remote_proxy_address = "http://127.0.0.1:4001"
+kme_address = "http://127.0.0.1:8080"
+
+[[pqkds]]
+port = 3001
+sae_id = "A1"
+remote_sae_id = "C0"
+remote_proxy_address = "http://127.0.0.1:4002"
+kme_address = "http://127.0.0.1:8081"
+"#;
+        toml::from_str(toml).expect("valid config")
+    }
+
+    #[test]
+    fn validate_accepts_consistent_files() {
+        assert!(validate(&square_config(), &square_hypercube()).is_ok());
+
+        // Relay ids need not be hypercube addresses.
+        let mut config = square_config();
+        let mut hypercube = square_hypercube();
+        for relay in hypercube.relay.iter_mut() {
+            relay.id = format!("relay-{}", relay.id);
+        }
+        config.id = format!("relay-{}", config.id);
+        assert!(validate(&config, &hypercube).is_ok());
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let mut config = square_config();
+        config.id = "000".to_string();
+        config.pqkds[1].remote_sae_id = "D0".to_string();
+        config.pqkds[1].port = 4000;
+        config.status_interval = Some(0);
+
+        let mut hypercube = square_hypercube();
+        hypercube.connection.push(Connection {
+            first: "A0".to_string(),
+            second: "Ghost".to_string(),
+            cost: None,
+            key_rate: None,
+            latency: None,
+        });
+        hypercube.threshold = Some(3);
+
+        let problems = validate(&config, &hypercube).expect_err("invalid").0;
+
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems[0].contains("`threshold` 3"));
+        assert!(problems[1].contains("\"Ghost\" does not belong to any relay"));
+        assert!(problems[2].contains("\"000\" from the config is missing"));
+        assert!(problems[3].contains("links SAE \"A1\" to its remote_sae_id \"D0\""));
+        assert!(problems[4].contains("port 4000"));
+        assert!(problems[5].contains("`status_interval`"));
+    }
+
+    #[test]
+    fn validate_rejects_negative_or_non_finite_costs() {
+        let mut hypercube = square_hypercube();
+        hypercube.weights.latency = -1.0;
+        hypercube.weights.depletion = f64::NAN;
+        hypercube.connection[0].cost = Some(-5.0);
+        hypercube.connection[1].key_rate = Some(f64::INFINITY);
+
+        let problems = validate(&square_config(), &hypercube)
+            .expect_err("invalid")
+            .0;
+
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].contains("weight `latency` -1"));
+        assert!(problems[1].contains("weight `depletion` NaN"));
+        assert!(problems[2].contains("`cost` -5"));
+        assert!(problems[3].contains("`key_rate` inf"));
+    }
+
     #[test]
     fn find_relay_returns_matching_relay_id_for_sae() {
         let hypercube = Hypercube {
             dimension: 2,
             n: 2,
+            paths: PathMode::Shortest,
+            threshold: None,
+            retries: 0,
             relay: vec![
                 Relay {
                     id: "00".to_string(),
@@ -300,9 +1574,13 @@ mod tests {
                     pqkds: vec!["Bob".to_string()],
                 },
             ],
+            weights: Weights::default(),
             connection: vec![Connection {
                 first: "Alice".to_string(),
                 second: "Bob".to_string(),
+                cost: None,
+                key_rate: None,
+                latency: None,
             }],
         };
 
@@ -1,6 +1,10 @@
+mod auth;
 mod error;
 mod server;
 mod state;
 
-pub use server::{DataKeys, EtsiServer, Key, KeyIds, Keys, Prom};
+pub use error::{ErrorBody, EtsiServerError};
+pub use server::{
+    DataKeys, EncKeysRequest, EtsiServer, HopMac, Key, KeyExtensions, KeyIds, Keys, Prom, Share,
+};
 pub use state::{AppStateEtsi, Client, KeyReceived};
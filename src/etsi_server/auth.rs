@@ -0,0 +1,71 @@
+use super::error::EtsiServerError;
+use crate::tls::Peer;
+use axum::{
+    extract::{Path, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Lets a request through only when the caller is the local SAE and the
+/// `sae_id` it names is another SAE: the master asks for `enc_keys` and
+/// `status` of its slaves, and the slave for `dec_keys` of its master.
+pub async fn authorize(
+    State(local): State<String>,
+    Path(sae_id): Path<String>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let caller = req.extensions().get::<Peer>().map(|c| c.0.clone());
+    match caller {
+        Some(caller) if caller == local && caller != sae_id => next.run(req).await,
+        caller => {
+            let caller = caller.unwrap_or_default();
+            tracing::warn!("SAE {} refused for {} on {}", caller, req.uri(), local);
+            EtsiServerError::Unauthorized(caller).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::authorize;
+    use crate::tls::tests::Pki;
+    use crate::tls::{acceptor, serve};
+    use axum::{middleware, routing::get, Router};
+
+    fn request(path: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+    }
+
+    #[tokio::test]
+    async fn facade_maps_client_certificates_to_saes() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let pki = Pki::new();
+        let tls = pki.server_tls(dir.path(), "identities = { \"alice.example\" = \"Alice\" }");
+
+        let app = Router::new()
+            .route("/api/v1/keys/:sae_id/status", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(
+                "Alice".to_string(),
+                authorize,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        let acceptor = acceptor(&tls).expect("acceptor");
+        tokio::spawn(serve(listener, acceptor, tls, app));
+
+        let bob = request("/api/v1/keys/Bob/status");
+        let ok = pki.send_as(port, "alice.example", &bob).await;
+        assert_eq!(ok, "HTTP/1.1 200 OK");
+        let other = pki.send_as(port, "Mallory", &bob).await;
+        assert_eq!(other, "HTTP/1.1 401 Unauthorized");
+        let alice = request("/api/v1/keys/Alice/status");
+        let itself = pki.send_as(port, "alice.example", &alice).await;
+        assert_eq!(itself, "HTTP/1.1 401 Unauthorized");
+    }
+}
@@ -1,6 +1,38 @@
+use axum::response::{IntoResponse, Response};
 use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use thiserror::Error;
 
+/// ETSI GS QKD 014 error body. The first entry of `details` carries a
+/// machine-readable `reason`; further entries add what the reason is about.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorBody {
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<Value>,
+}
+
+impl ErrorBody {
+    pub fn new(message: String, reason: &str, extra: Value, more: Vec<Value>) -> Self {
+        let mut detail = json!({ "reason": reason });
+        if let (Some(detail), Value::Object(extra)) = (detail.as_object_mut(), extra) {
+            detail.extend(extra);
+        }
+        let mut details = vec![detail];
+        details.extend(more);
+        ErrorBody { message, details }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.details.first()?.get("reason")?.as_str()
+    }
+
+    pub fn into_response(self, status: StatusCode) -> Response {
+        (status, axum::Json(self)).into_response()
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum EtsiServerError {
     #[error("io error")]
@@ -29,13 +61,153 @@ pub enum EtsiServerError {
     PqkdRequestError(StatusCode),
     #[error("Get keys error")]
     GetKeysError,
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Not all extension_mandatory parameters are supported")]
+    UnsupportedExtensions(Vec<String>),
+    #[error("No keys found for the given key IDs")]
+    KeysNotFound,
+    #[error("Failed relay request: statuscode - {0}")]
+    RelayRequestError(StatusCode, Option<ErrorBody>),
+    #[error("TLS setup failed: {0}")]
+    TlsConfigError(#[from] crate::tls::TlsError),
+    #[error("Caller {0} is not authorised for this request")]
+    Unauthorized(String),
+    #[error("Attesting the hop failed: {0}")]
+    AttestationError(#[from] crate::attestation::AttestationError),
+}
+
+impl EtsiServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            EtsiServerError::UnknownPqkd(_) | EtsiServerError::Unauthorized(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            EtsiServerError::PathError => StatusCode::SERVICE_UNAVAILABLE,
+            EtsiServerError::InvalidRequest(_) | EtsiServerError::UnsupportedExtensions(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            EtsiServerError::KeysNotFound => StatusCode::NOT_FOUND,
+            EtsiServerError::PqkdRequestError(code) => *code,
+            // A relay that is out of room asks the caller to back off; any
+            // other failure further down the path is a bad gateway here.
+            EtsiServerError::RelayRequestError(StatusCode::SERVICE_UNAVAILABLE, _) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            EtsiServerError::ClientError(_)
+            | EtsiServerError::SerdeJsonError(_)
+            | EtsiServerError::Base64DecodeError(_)
+            | EtsiServerError::SendKeysError
+            | EtsiServerError::RelayRequestError(..) => StatusCode::BAD_GATEWAY,
+            EtsiServerError::IoError(_)
+            | EtsiServerError::UriError(_)
+            | EtsiServerError::TlsError(_)
+            | EtsiServerError::AxumError(_)
+            | EtsiServerError::HttpError(_)
+            | EtsiServerError::GetKeysError
+            | EtsiServerError::TlsConfigError(_)
+            | EtsiServerError::AttestationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            EtsiServerError::IoError(_) => "io_error",
+            EtsiServerError::UriError(_) => "invalid_uri",
+            EtsiServerError::TlsError(_) => "tls_error",
+            EtsiServerError::ClientError(_) => "upstream_unreachable",
+            EtsiServerError::AxumError(_) => "body_error",
+            EtsiServerError::HttpError(_) => "http_error",
+            EtsiServerError::SerdeJsonError(_) => "invalid_upstream_response",
+            EtsiServerError::Base64DecodeError(_) => "invalid_key_encoding",
+            EtsiServerError::UnknownPqkd(_) => "unknown_sae",
+            EtsiServerError::PathError => "no_path",
+            EtsiServerError::SendKeysError => "send_keys_failed",
+            EtsiServerError::PqkdRequestError(_) => "kme_request_failed",
+            EtsiServerError::GetKeysError => "key_store_error",
+            EtsiServerError::InvalidRequest(_) => "invalid_request",
+            EtsiServerError::UnsupportedExtensions(_) => "extension_mandatory_unsupported",
+            EtsiServerError::KeysNotFound => "key_not_found",
+            EtsiServerError::RelayRequestError(..) => "relay_request_failed",
+            EtsiServerError::TlsConfigError(_) => "tls_error",
+            EtsiServerError::Unauthorized(_) => "unauthorized",
+            EtsiServerError::AttestationError(_) => "attestation_error",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let (extra, more) = match self {
+            EtsiServerError::UnknownPqkd(sae_id) | EtsiServerError::Unauthorized(sae_id) => {
+                (json!({ "sae_id": sae_id }), Vec::new())
+            }
+            EtsiServerError::PqkdRequestError(code) => {
+                (json!({ "status": code.as_u16() }), Vec::new())
+            }
+            EtsiServerError::UnsupportedExtensions(names) => (
+                Value::Null,
+                names
+                    .iter()
+                    .map(|name| json!({ "extension_mandatory_unsupported": name }))
+                    .collect(),
+            ),
+            // The relay's own reason follows, so clients see e.g. a full store.
+            EtsiServerError::RelayRequestError(code, body) => (
+                json!({ "status": code.as_u16() }),
+                body.as_ref().map(|b| b.details.clone()).unwrap_or_default(),
+            ),
+            _ => (Value::Null, Vec::new()),
+        };
+        ErrorBody::new(self.to_string(), self.reason(), extra, more)
+    }
+}
+
+impl IntoResponse for EtsiServerError {
+    fn into_response(self) -> Response {
+        self.body().into_response(self.status())
+    }
 }
 
 impl From<EtsiServerError> for StatusCode {
-    fn from(_val: EtsiServerError) -> Self {
-        //match val {
-        //    _ => StatusCode::INTERNAL_SERVER_ERROR,
-        //}
-        StatusCode::INTERNAL_SERVER_ERROR
+    fn from(val: EtsiServerError) -> Self {
+        val.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorBody, EtsiServerError};
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    #[test]
+    fn errors_map_to_etsi_bodies_with_a_reason() {
+        let err = EtsiServerError::UnknownPqkd("Mallory".to_string());
+        assert_eq!(err.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            serde_json::to_value(err.body()).expect("json"),
+            json!({
+                "message": "Server dont't have data about pqkd with sae_id Mallory",
+                "details": [{ "reason": "unknown_sae", "sae_id": "Mallory" }]
+            })
+        );
+        assert_eq!(
+            EtsiServerError::PathError.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let relay = ErrorBody::new(
+            "No room left for keys from Alice.".to_string(),
+            "store_full",
+            json!({ "sae_id": "Alice" }),
+            Vec::new(),
+        );
+        let err = EtsiServerError::RelayRequestError(StatusCode::SERVICE_UNAVAILABLE, Some(relay));
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = err.body();
+        assert_eq!(body.reason(), Some("relay_request_failed"));
+        assert_eq!(body.details[1]["reason"], "store_full");
+
+        let err = EtsiServerError::RelayRequestError(StatusCode::INTERNAL_SERVER_ERROR, None);
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
     }
 }
@@ -1,11 +1,15 @@
+use super::auth;
 use super::error::EtsiServerError;
-use super::state::AppStateEtsi;
-use crate::config::{build_hypercube, find_n_shortest_paths, Pqkd};
+use super::state::{AppStateEtsi, Client};
+use crate::attestation::Attestation;
+use crate::config::{PathMode, Pqkd, ServerTls};
+use crate::tls;
 use crate::util;
 use axum::{
     body::Body,
     extract::{Path, Request, State},
     http::{header, uri::Uri},
+    middleware,
     response::{IntoResponse, Response},
     routing::{get, post},
     Router,
@@ -17,15 +21,33 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
 use tracing::Span;
+use zeroize::Zeroizing;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Key {
-    pub key: String,
+    pub key: Zeroizing<String>,
     #[serde(rename(deserialize = "key_ID"))]
     #[serde(rename(serialize = "key_ID"))]
     pub key_id: String,
+    #[serde(flatten)]
+    pub extensions: KeyExtensions,
+}
+
+/// Vendor extension data of a key, passed on untouched from the KME that
+/// issued it to the SAE that receives it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KeyExtensions {
+    #[serde(
+        rename = "key_ID_extension",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub key_id_extension: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_extension: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,6 +60,12 @@ pub struct KeyId {
     #[serde(rename(deserialize = "key_ID"))]
     #[serde(rename(serialize = "key_ID"))]
     pub key_id: String,
+    #[serde(
+        rename = "key_ID_extension",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub key_id_extension: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -54,6 +82,69 @@ struct DecKeysQuery {
     key_id: String,
 }
 
+/// Most additional slave SAEs a relayed `enc_keys` request may name.
+const MAX_SAE_ID_COUNT: usize = 8;
+
+/// Mandatory extensions relayed `enc_keys` requests can honour.
+const SUPPORTED_EXTENSIONS: &[&str] = &[];
+
+/// ETSI GS QKD 014 `enc_keys` request, from the query of a GET or the body
+/// of a POST.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct EncKeysRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(
+        rename = "additional_slave_SAE_IDs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub additional_slave_sae_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extension_mandatory: Vec<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extension_optional: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl EncKeysRequest {
+    /// Every slave SAE the keys go to: the one in the URL, then the
+    /// additional ones without repeats.
+    pub fn slaves(&self, sae_id: &str) -> Vec<String> {
+        let mut slaves = vec![sae_id.to_string()];
+        for id in self.additional_slave_sae_ids.iter() {
+            if !slaves.contains(id) {
+                slaves.push(id.clone());
+            }
+        }
+        slaves
+    }
+
+    /// Names of the mandatory extensions that cannot be honoured.
+    pub fn unsupported_extensions(&self) -> Vec<&str> {
+        self.extension_mandatory
+            .iter()
+            .flat_map(|ext| ext.keys())
+            .map(String::as_str)
+            .filter(|name| !SUPPORTED_EXTENSIONS.contains(name))
+            .collect()
+    }
+
+    /// Query string asking the KME for the keys, without the relay-only
+    /// options it does not know about.
+    fn kme_query(&self) -> String {
+        let mut query = Vec::new();
+        if let Some(number) = self.number {
+            query.push(format!("number={}", number));
+        }
+        if let Some(size) = self.size {
+            query.push(format!("size={}", size));
+        }
+        query.join("&")
+    }
+}
+
 impl Keys {
     pub fn keys(self) -> Vec<Key> {
         self.keys
@@ -65,14 +156,22 @@ pub struct Prom {
     key_id: String,
     key_id_xor: Option<String>,
     key: Option<Vec<u8>>,
+    #[serde(flatten)]
+    extensions: KeyExtensions,
 }
 
 impl Prom {
-    pub fn new(key_id: String, key_id_xor: Option<String>, key: Option<Vec<u8>>) -> Self {
+    pub fn new(
+        key_id: String,
+        key_id_xor: Option<String>,
+        key: Option<Vec<u8>>,
+        extensions: KeyExtensions,
+    ) -> Self {
         Self {
             key_id,
             key_id_xor,
             key,
+            extensions,
         }
     }
 
@@ -87,26 +186,124 @@ impl Prom {
     pub fn key(&self) -> &Option<Vec<u8>> {
         &self.key
     }
+
+    pub fn extensions(&self) -> &KeyExtensions {
+        &self.extensions
+    }
+}
+
+/// Position of the share carried by a `DataKeys` message among all shares of
+/// the same keys. Without `threshold` the shares are XOR-combined and all of
+/// them are needed; with it they are Shamir shares and any `threshold` of
+/// them rebuild the keys.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Share {
+    pub index: usize,
+    pub total: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<usize>,
+}
+
+/// Tag authenticating one hop of a [`DataKeys`], keyed by the link key
+/// `key_ID` that the receiving relay fetches with `dec_keys`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HopMac {
+    #[serde(rename = "key_ID")]
+    pub key_id: String,
+    /// Base64 of the 16-byte tag.
+    pub tag: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DataKeys {
     from: String,
     to: String,
+    /// Random id of this hop's message; a relay accepts each id only once.
+    #[serde(default)]
+    transfer_id: String,
+    /// When the message was sent, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    timestamp: u64,
     path: Vec<String>,
+    share: Share,
     keys: Vec<Prom>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attestations: Vec<Attestation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<HopMac>,
 }
 
 impl DataKeys {
-    pub fn new(from: String, to: String, path: Vec<String>, keys: Vec<Prom>) -> Self {
+    pub fn new(from: String, to: String, path: Vec<String>, share: Share, keys: Vec<Prom>) -> Self {
         Self {
             from,
             to,
+            transfer_id: BASE64_URL_SAFE_NO_PAD.encode(util::random_bytes(16)),
+            timestamp: util::unix_millis(),
             path,
+            share,
             keys,
+            attestations: Vec::new(),
+            mac: None,
         }
     }
 
+    /// Carries over the attestations of the hops before this one.
+    pub fn with_attestations(mut self, attestations: Vec<Attestation>) -> Self {
+        self.attestations = attestations;
+        self
+    }
+
+    /// Everything the hop MAC covers: the whole message but the tag.
+    fn authenticated(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(&(
+            &self.from,
+            &self.to,
+            &self.transfer_id,
+            self.timestamp,
+            &self.path,
+            &self.share,
+            &self.keys,
+            &self.attestations,
+        ))
+    }
+
+    /// Tags the message with the one-time link key `key` named `key_id`.
+    /// Returns `false`, leaving it untagged, when `key` is not
+    /// [`util::MAC_KEY_LEN`] bytes.
+    pub fn seal(&mut self, key_id: String, key: &[u8]) -> Result<bool, serde_json::Error> {
+        let Some(tag) = util::mac(key, &self.authenticated()?) else {
+            return Ok(false);
+        };
+        self.mac = Some(HopMac {
+            key_id,
+            tag: BASE64_STANDARD.encode(tag),
+        });
+        Ok(true)
+    }
+
+    /// Whether the message carries a tag made with `key`.
+    pub fn verify(&self, key: &[u8]) -> bool {
+        let (Some(mac), Ok(message)) = (&self.mac, self.authenticated()) else {
+            return false;
+        };
+        BASE64_STANDARD
+            .decode(&mac.tag)
+            .is_ok_and(|tag| util::verify_mac(key, &message, &tag))
+    }
+
+    pub fn mac(&self) -> Option<&HopMac> {
+        self.mac.as_ref()
+    }
+
+    pub fn attestations(&self) -> &[Attestation] {
+        &self.attestations
+    }
+
+    pub fn push_attestation(&mut self, attestation: Attestation) {
+        self.attestations.push(attestation);
+    }
+
     pub fn from(&self) -> &str {
         &self.from
     }
@@ -115,68 +312,158 @@ impl DataKeys {
         &self.to
     }
 
+    pub fn transfer_id(&self) -> &str {
+        &self.transfer_id
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
     pub fn path(&self) -> &Vec<String> {
         &self.path
     }
 
+    pub fn share(&self) -> Share {
+        self.share
+    }
+
     pub fn keys(&self) -> &Vec<Prom> {
         &self.keys
     }
 }
 
+/// ETSI GS QKD 014 status of the link between two SAEs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    #[serde(rename = "source_KME_ID")]
+    pub source_kme_id: String,
+    /// Left out for a relayed SAE: its KME sits behind another relay, which
+    /// exposes no KME status, so its ID is not known here.
+    #[serde(
+        rename = "target_KME_ID",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub target_kme_id: Option<String>,
+    #[serde(rename = "master_SAE_ID")]
+    pub master_sae_id: String,
+    #[serde(rename = "slave_SAE_ID")]
+    pub slave_sae_id: String,
+    pub key_size: u64,
+    pub stored_key_count: u64,
+    pub max_key_count: u64,
+    pub max_key_per_request: u64,
+    pub max_key_size: u64,
+    pub min_key_size: u64,
+    #[serde(rename = "max_SAE_ID_count")]
+    pub max_sae_id_count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_extension: Option<serde_json::Value>,
+}
+
+impl Status {
+    /// Status of a relayed pair from the statuses of the local links a
+    /// relayed key draws on, each with the number of keys it gives per
+    /// relayed key. The first link is the master's own, whose KME issues the
+    /// key IDs. Counts and limits are those of the tightest link; keys must
+    /// fit every link, so sizes are bounded by all of them. The slave's KME
+    /// is not known, so `target_KME_ID` is left out.
+    fn relayed(master_sae_id: &str, slave_sae_id: &str, links: &[(Status, u64)]) -> Option<Status> {
+        let (own, _) = links.first()?;
+        let tightest = |count: fn(&Status) -> u64| {
+            links
+                .iter()
+                .map(|(s, uses)| count(s) / uses.max(&1))
+                .min()
+                .unwrap_or_default()
+        };
+        Some(Status {
+            source_kme_id: own.source_kme_id.clone(),
+            target_kme_id: None,
+            master_sae_id: master_sae_id.to_string(),
+            slave_sae_id: slave_sae_id.to_string(),
+            key_size: own.key_size,
+            stored_key_count: tightest(|s| s.stored_key_count),
+            max_key_count: tightest(|s| s.max_key_count),
+            max_key_per_request: links.iter().map(|(s, _)| s.max_key_per_request).min()?,
+            max_key_size: links.iter().map(|(s, _)| s.max_key_size).min()?,
+            min_key_size: links.iter().map(|(s, _)| s.min_key_size).max()?,
+            max_sae_id_count: MAX_SAE_ID_COUNT as u64,
+            status_extension: None,
+        })
+    }
+}
+
 pub struct EtsiServer {
     app: Router,
     listener: TcpListener,
+    tls: Option<(TlsAcceptor, ServerTls)>,
 }
 
 impl EtsiServer {
     pub async fn build(state: AppStateEtsi, pqkd: &Pqkd) -> Result<EtsiServer, EtsiServerError> {
-        let app = Router::new()
+        let local = state.sae_id().to_string();
+        let mut app = Router::new()
             .route("/api/v1/keys/:sae_id/status", get(status))
             .route("/api/v1/keys/:sae_id/enc_keys", get(enc_keys))
             .route("/api/v1/keys/:sae_id/enc_keys", post(enc_keys))
             .route("/api/v1/keys/:sae_id/dec_keys", get(dec_keys))
             .route("/api/v1/keys/:sae_id/dec_keys", post(dec_keys))
-            .with_state(state)
-            .layer(
-                TraceLayer::new_for_http()
-                    .make_span_with(|request: &Request<_>| {
-                        // Log the matched route's path (with placeholders not filled in).
-                        // Use request.uri() or OriginalUri if you want the real path.
-                        let matched_path = request
-                            .extensions()
-                            .get::<MatchedPath>()
-                            .map(MatchedPath::as_str);
-
-                        tracing::info_span!(
-                            "http_request",
-                            //status_code = tracing::field::Empty,
-                            method = ?request.method(),
-                            matched_path,
-                            status_code = tracing::field::Empty,
-                        )
-                    })
-                    .on_request(|_request: &Request<_>, _span: &Span| {})
-                    .on_response(|_response: &Response, _latency: Duration, _span: &Span| {
-                        _span.record("status_code", tracing::field::display(_response.status()));
-                    })
-                    .on_body_chunk(|_chunk: &Bytes, _latency: Duration, _span: &Span| {})
-                    .on_eos(
-                        |_trailers: Option<&HeaderMap>,
-                         _stream_duration: Duration,
-                         _span: &Span| {},
+            .with_state(state);
+        let tls = match pqkd.tls() {
+            Some(config) => {
+                app = app.route_layer(middleware::from_fn_with_state(local, auth::authorize));
+                Some((tls::acceptor(config)?, config.clone()))
+            }
+            None => {
+                tracing::warn!(
+                    "ETSI façade of {} serves plain HTTP: any client can act as any SAE",
+                    pqkd.sae_id()
+                );
+                None
+            }
+        };
+        let app = app.layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<_>| {
+                    // Log the matched route's path (with placeholders not filled in).
+                    // Use request.uri() or OriginalUri if you want the real path.
+                    let matched_path = request
+                        .extensions()
+                        .get::<MatchedPath>()
+                        .map(MatchedPath::as_str);
+
+                    tracing::info_span!(
+                        "http_request",
+                        //status_code = tracing::field::Empty,
+                        method = ?request.method(),
+                        matched_path,
+                        status_code = tracing::field::Empty,
                     )
-                    .on_failure(
-                        |_error: ServerErrorsFailureClass, _latency: Duration, _span: &Span| {},
-                    ),
-            );
+                })
+                .on_request(|_request: &Request<_>, _span: &Span| {})
+                .on_response(|_response: &Response, _latency: Duration, _span: &Span| {
+                    _span.record("status_code", tracing::field::display(_response.status()));
+                })
+                .on_body_chunk(|_chunk: &Bytes, _latency: Duration, _span: &Span| {})
+                .on_eos(
+                    |_trailers: Option<&HeaderMap>, _stream_duration: Duration, _span: &Span| {},
+                )
+                .on_failure(
+                    |_error: ServerErrorsFailureClass, _latency: Duration, _span: &Span| {},
+                ),
+        );
         let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", pqkd.port())).await?;
 
-        Ok(EtsiServer { app, listener })
+        Ok(EtsiServer { app, listener, tls })
     }
 
     pub async fn run(self) -> Result<(), std::io::Error> {
-        axum::serve(self.listener, self.app).await
+        match self.tls {
+            Some((acceptor, config)) => tls::serve(self.listener, acceptor, config, self.app).await,
+            None => axum::serve(self.listener, self.app).await,
+        }
     }
 }
 
@@ -186,11 +473,11 @@ async fn status(
     req: Request,
 ) -> Response {
     tracing::info!("Status with {}", sae_id);
-    match h(state, req).await {
+    match _status(sae_id, state, req).await {
         Ok(res) => res,
         Err(e) => {
             tracing::error!("{}", e);
-            error_response(e)
+            e.into_response()
         }
     }
 }
@@ -205,7 +492,7 @@ async fn enc_keys(
         Ok(res) => res,
         Err(e) => {
             tracing::error!("Transfer keys failed: {}", e);
-            error_response(e)
+            e.into_response()
         }
     }
 }
@@ -220,7 +507,7 @@ async fn dec_keys(
         Ok(res) => res,
         Err(e) => {
             tracing::error!("{}", e);
-            error_response(e)
+            e.into_response()
         }
     }
 }
@@ -228,185 +515,369 @@ async fn dec_keys(
 async fn _enc_keys(
     sae_id: String,
     state: AppStateEtsi,
-    mut req: Request,
+    req: Request,
 ) -> Result<Response, EtsiServerError> {
     let pqkd = state
         .pqkd(|p| p.sae_id() == state.sae_id())
         .ok_or(EtsiServerError::UnknownPqkd(state.sae_id().to_string()))?;
-    if pqkd.remote_sae_id() == sae_id {
-        h(state, req).await
-    } else {
-        let end = state
-            .hypercube()
-            .find_relay(&sae_id)
-            .ok_or(EtsiServerError::PathError)?;
-        let hypercube = build_hypercube(state.hypercube().dimension());
-        let paths = find_n_shortest_paths(&hypercube, state.id_relay(), end, state.hypercube().n());
-
-        let mut paths_sae_id = Vec::new();
-
-        for path in paths {
-            let mut v: Vec<String> = Vec::new();
-
-            let mut p = Vec::new();
-            for i in path.iter() {
-                let relay = state
-                    .hypercube()
-                    .relay()
-                    .iter()
-                    .find(|r| r.id() == i)
-                    .ok_or(EtsiServerError::PathError)?;
-                p.push(relay.pqkds());
-            }
-            let c = state.hypercube().connection();
-            for i in 0..p.len() - 1 {
-                for sae_id in p[i] {
-                    let con = c
-                        .iter()
-                        .find(|con| con.first() == sae_id || con.second() == sae_id)
-                        .ok_or(EtsiServerError::PathError)?;
-
-                    let s_r = if con.first() == sae_id {
-                        con.second()
-                    } else {
-                        con.first()
-                    };
 
-                    let sae_id_r = p[i + 1].iter().find(|s| s == &s_r);
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await?;
+    let request: EncKeysRequest = match parts.method {
+        Method::POST if !body.is_empty() => match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return Err(EtsiServerError::InvalidRequest(e.to_string())),
+        },
+        _ => match serde_qs::from_str(parts.uri.query().unwrap_or_default()) {
+            Ok(request) => request,
+            Err(e) => return Err(EtsiServerError::InvalidRequest(e.to_string())),
+        },
+    };
 
-                    if let Some(s) = sae_id_r {
-                        v.push(String::from(sae_id));
-                        v.push(String::from(s));
-                        break;
-                    }
-                }
-            }
-            let last = v.last().ok_or(EtsiServerError::PathError)?;
-            if last != &sae_id {
-                v.push(sae_id.clone());
+    let slaves = request.slaves(&sae_id);
+    if slaves == [pqkd.remote_sae_id()] {
+        return h(state, Request::from_parts(parts, Body::from(body))).await;
+    }
+
+    // The KME only knows the direct partner; everything it does not know
+    // about is up to the relay.
+    let unsupported = request.unsupported_extensions();
+    if !unsupported.is_empty() {
+        return Err(EtsiServerError::UnsupportedExtensions(
+            unsupported.iter().map(|name| name.to_string()).collect(),
+        ));
+    }
+    if slaves.len() - 1 > MAX_SAE_ID_COUNT {
+        return Err(EtsiServerError::InvalidRequest(format!(
+            "at most {} additional_slave_SAE_IDs are supported",
+            MAX_SAE_ID_COUNT
+        )));
+    }
+    if slaves.iter().any(|s| s == pqkd.remote_sae_id()) {
+        return Err(EtsiServerError::InvalidRequest(format!(
+            "{} gets its keys from the KME and cannot share relayed keys",
+            pqkd.remote_sae_id()
+        )));
+    }
+
+    // Plan every slave before any key is taken from the KME.
+    let mut routes = Vec::new();
+    for slave in slaves.iter() {
+        routes.push((slave.clone(), plan_paths(&state, slave)?));
+    }
+
+    let mut uri = format!(
+        "{}/api/v1/keys/{}/enc_keys",
+        pqkd.kme_address(),
+        pqkd.remote_sae_id()
+    );
+    let query = request.kme_query();
+    if !query.is_empty() {
+        uri = format!("{}?{}", uri, query);
+    }
+    let kme_request = hyper::Request::builder()
+        .method(Method::GET)
+        .uri(Uri::try_from(uri)?)
+        .body(Body::empty())?;
+    let response = state.client().request(kme_request).await?.into_response();
+
+    if response.status() != StatusCode::OK {
+        return Err(EtsiServerError::PqkdRequestError(response.status()));
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body_bytes = axum::body::to_bytes(body, usize::MAX).await?;
+
+    // The KME's key material is discarded and only its key IDs and sizes are
+    // kept. The delivered key is generated here and only transported, split
+    // into shares masked with QKD keys of each link as one-time pads, so
+    // neither the first-hop KME nor any single relay learns it.
+    let mut body_json: serde_json::Value = serde_json::from_slice(&body_bytes[..])?;
+    let keys: Keys = serde_json::from_value(body_json.clone())?;
+    let mut keys = keys.keys();
+    for (i, key) in keys.iter_mut().enumerate() {
+        let size = BASE64_STANDARD.decode(&key.key)?.len();
+        key.key = BASE64_STANDARD.encode(util::random_bytes(size)).into();
+        body_json["keys"][i]["key"] = serde_json::Value::String(key.key.to_string());
+    }
+
+    // Every slave gets the same keys, each along its own paths.
+    let st = Arc::new(state);
+    let mut transfers = tokio::task::JoinSet::new();
+    for (slave, route) in routes {
+        let st = Arc::clone(&st);
+        let keys = keys.clone();
+        transfers.spawn(async move {
+            let res = relay_keys(st, route, keys).await;
+            (slave, res)
+        });
+    }
+    let mut error = None;
+    while let Some(transfer) = transfers.join_next().await {
+        match transfer {
+            Ok((slave, Ok(()))) => tracing::info!("Transfer keys to {}: Succeces", slave),
+            Ok((slave, Err(e))) => {
+                tracing::error!("Transfer keys to {} failed: {}", slave, e);
+                error.get_or_insert(e);
             }
-            let first = v.first().ok_or(EtsiServerError::PathError)?;
-            if first != state.sae_id() {
-                v.insert(0, String::from(state.sae_id()));
+            Err(e) => {
+                tracing::error!("Transfer keys task failed: {}", e);
+                error.get_or_insert(EtsiServerError::SendKeysError);
             }
-            paths_sae_id.push(v);
         }
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
 
-        //let p = paths_sae_id
-        //    .iter()
-        //    .position(|i| i[1] == pqkd.remote_sae_id())
-        //    .unwrap();
-        //    0 -> p
-        if paths_sae_id.is_empty() {
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Response::from_parts(
+        parts,
+        Body::from(serde_json::to_vec(&body_json)?),
+    ))
+}
+
+/// SAE hop lists of the paths keys for one slave SAE take, and of the spare
+/// paths that replace failed ones, up to the `retries` budget.
+struct Route {
+    paths: Vec<Vec<String>>,
+    spares: Vec<Vec<String>>,
+}
+
+fn plan_paths(state: &AppStateEtsi, sae_id: &str) -> Result<Route, EtsiServerError> {
+    let end = state
+        .hypercube()
+        .find_relay(sae_id)
+        .ok_or_else(|| EtsiServerError::UnknownPqkd(sae_id.to_string()))?;
+    let paths = state
+        .hypercube()
+        .find_paths(state.id_relay(), end, state.health());
+    if state.hypercube().path_mode() == PathMode::Disjoint && paths.len() < state.hypercube().n() {
+        tracing::warn!(
+            "Only {} of {} node-disjoint paths to {} exist",
+            paths.len(),
+            state.hypercube().n(),
+            end
+        );
+    }
+
+    let mut paths_sae_id = Vec::new();
+    for path in paths.iter() {
+        paths_sae_id.push(sae_path(state, path, sae_id)?);
+    }
+
+    let mut spares: Vec<Vec<String>> = state
+        .hypercube()
+        .find_spare_paths(state.id_relay(), end, state.health(), &paths)
+        .iter()
+        .filter_map(|path| sae_path(state, path, sae_id).ok())
+        .collect();
+    spares.reverse();
+
+    if paths_sae_id.is_empty() {
+        return Err(EtsiServerError::PathError);
+    }
+    if let Some(k) = state.hypercube().threshold() {
+        if k == 0 || paths_sae_id.len() < k {
+            tracing::error!(
+                "Threshold {} needs at least {} paths, found {}",
+                k,
+                k,
+                paths_sae_id.len()
+            );
             return Err(EtsiServerError::PathError);
         }
-        let path = paths_sae_id.swap_remove(0);
-
-        tracing::info!("Main path: {:?}", path);
-        tracing::info!("Other path: {:?}", paths_sae_id);
-
-        let uri = match (req.method(), req.uri().query()) {
-            (&Method::GET, Some(query)) => {
-                format!(
-                    "{}/api/v1/keys/{}/enc_keys?{}",
-                    pqkd.kme_address(),
-                    pqkd.remote_sae_id(),
-                    query
-                )
-            }
-            _ => {
-                format!(
-                    "{}/api/v1/keys/{}/enc_keys",
-                    pqkd.kme_address(),
-                    pqkd.remote_sae_id()
-                )
-            }
+    }
+
+    tracing::info!("Paths to {}: {:?}", sae_id, paths_sae_id);
+    Ok(Route {
+        paths: paths_sae_id,
+        spares,
+    })
+}
+
+/// Splits `keys` into one share per path and sends them, retrying failed
+/// shares on spare paths.
+async fn relay_keys(
+    st: Arc<AppStateEtsi>,
+    route: Route,
+    keys: Vec<Key>,
+) -> Result<(), EtsiServerError> {
+    let Route { paths, mut spares } = route;
+    let total = paths.len();
+    let threshold = st.hypercube().threshold();
+    let mut shares: Vec<Vec<Key>> = vec![Vec::new(); total];
+    for key in keys.iter() {
+        let secret = BASE64_STANDARD.decode(&key.key)?;
+        let split = match threshold {
+            Some(k) => util::shamir_split(&secret, k, total),
+            None => util::xor_split(&secret, total),
         };
-        *req.uri_mut() = Uri::try_from(uri)?;
-        let response = state.client().request(req).await?.into_response();
+        for (i, share) in split.into_iter().enumerate() {
+            shares[i].push(Key {
+                key: BASE64_STANDARD.encode(share).into(),
+                key_id: key.key_id.clone(),
+                extensions: key.extensions.clone(),
+            });
+        }
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
 
-        if response.status() != StatusCode::OK {
-            return Err(EtsiServerError::PqkdRequestError(response.status()));
+    for (index, p) in paths.into_iter().enumerate() {
+        let share = Share {
+            index,
+            total,
+            threshold,
+        };
+        spawn_send_keys(&st, &tx, p, share, shares[index].clone());
+    }
+
+    // With a threshold the keys survive `total - k` lost shares.
+    let required = threshold.unwrap_or(total);
+    let mut pending = total;
+    let mut delivered = 0;
+    let mut error = None;
+    while pending > 0 {
+        let Some((share, res)) = rx.recv().await else {
+            break;
+        };
+        pending -= 1;
+        match res {
+            Ok(()) => delivered += 1,
+            Err(e) => {
+                tracing::error!("Share {}/{} failed: {:?}", share.index + 1, total, e);
+                error.get_or_insert(e);
+                if let Some(p) = spares.pop() {
+                    tracing::info!("Retry share {}/{} on {:?}", share.index + 1, total, p);
+                    spawn_send_keys(&st, &tx, p, share, shares[share.index].clone());
+                    pending += 1;
+                }
+            }
         }
+    }
+    if delivered < required {
+        return Err(error.unwrap_or(EtsiServerError::SendKeysError));
+    }
+    Ok(())
+}
 
-        let (parts, body) = response.into_parts();
-        let body_bytes = axum::body::to_bytes(body, usize::MAX).await?;
+/// Translates a relay path into the SAE hop list used by `send_keys`, from
+/// the local SAE to `sae_id`.
+fn sae_path(
+    state: &AppStateEtsi,
+    relay_path: &[String],
+    sae_id: &str,
+) -> Result<Vec<String>, EtsiServerError> {
+    state
+        .hypercube()
+        .sae_path(relay_path, state.sae_id(), sae_id, state.health())
+        .ok_or(EtsiServerError::PathError)
+}
+
+fn spawn_send_keys(
+    state: &Arc<AppStateEtsi>,
+    tx: &tokio::sync::mpsc::Sender<(Share, Result<(), EtsiServerError>)>,
+    path: Vec<String>,
+    share: Share,
+    keys: Vec<Key>,
+) {
+    let state = Arc::clone(state);
+    let tx = tx.clone();
+    tokio::task::spawn(async move {
+        tracing::info!(
+            "SEND KEY share {}/{} path {:?}",
+            share.index + 1,
+            share.total,
+            path
+        );
+        let res = send_keys(state, path, share, keys).await;
+        if tx.send((share, res)).await.is_err() {
+            tracing::error!("Failed to send result from worker");
+        }
+    });
+}
 
-        let keys: Keys = serde_json::from_slice(&body_bytes[..])?;
-        let keys = keys.keys();
+async fn _status(
+    sae_id: String,
+    state: AppStateEtsi,
+    req: Request,
+) -> Result<Response, EtsiServerError> {
+    let pqkd = state
+        .pqkd(|p| p.sae_id() == state.sae_id())
+        .ok_or(EtsiServerError::UnknownPqkd(state.sae_id().to_string()))?;
 
-        //let mut list_handles = Vec::new();
+    if pqkd.remote_sae_id() == sae_id {
+        return h(state, req).await;
+    }
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-        let st = Arc::new(state);
-        let ks = Arc::new(keys);
-        // let p = paths_sae_id.get(0).unwrap().clone();
+    // The KME does not know a relayed SAE, so the status is built from the
+    // paths keys would take and the status of the local links they draw on.
+    let end = state
+        .hypercube()
+        .find_relay(&sae_id)
+        .ok_or_else(|| EtsiServerError::UnknownPqkd(sae_id.clone()))?;
+    let mut paths = Vec::new();
+    for path in state
+        .hypercube()
+        .find_paths(state.id_relay(), end, state.health())
+        .iter()
+    {
+        paths.push(sae_path(&state, path, &sae_id)?);
+    }
+    if paths.is_empty() {
+        return Err(EtsiServerError::PathError);
+    }
 
-        for p in paths_sae_id {
-            let tx = tx.clone();
-            let st = Arc::clone(&st);
-            let ks = Arc::clone(&ks);
-            tokio::task::spawn(async move {
-                tracing::info!("SEND KEY path {:?}", p);
-                let res = send_keys(st, p, ks).await;
-                if tx.send(res).await.is_err() {
-                    tracing::error!("Failed to send result from worker");
-                }
-            });
+    let mut links: Vec<(&Pqkd, u64)> = vec![(pqkd, 1)];
+    for path in paths.iter() {
+        let link = first_link(&state, path).ok_or(EtsiServerError::PathError)?;
+        match links.iter_mut().find(|(p, _)| p.sae_id() == link.sae_id()) {
+            Some((_, uses)) => *uses += 1,
+            None => links.push((link, 1)),
         }
-        // tokio::task::spawn(async move {
-        //     tracing::info!("SEND KEY path {:?}", p);
-        //     let res = send_keys(st, p, ks).await;
-        //     tx.send(res).await.unwrap();
-        // });
-
-        tokio::task::spawn(async move {
-            tracing::info!("SEND KEY path {:?}", path);
-            let res = send_keys(st, path, ks).await;
-            if tx.send(res).await.is_err() {
-                tracing::error!("Failed to send result from worker");
-            }
-        });
+    }
+    let mut statuses = Vec::new();
+    for (link, uses) in links {
+        statuses.push((kme_status(&state, link).await?, uses));
+    }
 
-        //list_handles.push(h);
-
-        //let response_1 = send_keys(&state, path, &keys).await;
-
-        //let mut responses = Vec::new();
-
-        // match send_keys(state, path, keys).await {
-        //     Ok(_) => {
-        //         tracing::info!("Transfer keys: Succeces");
-        //         Ok(Response::from_parts(parts, Body::from(body_bytes)))
-        //     } // todo ERROR zwrocic komunikat
-        //     Err(e) => Err(e),
-        // }
-
-        // if let Err(e) = response_1 {
-        //     tracing::info!("Transfer keys: Failed. {:?}", e);
-        //     return Err(e);
-        // } else {
-        //     tracing::info!("Transfer keys: Succeces");
-        // }
-        //
-        // for r in responses {
-        //     if let Err(e) = r {
-        //         tracing::info!("Transfer keys: Failed. {:?}", e);
-        //         return Err(e);
-        //     } else {
-        //         tracing::info!("Transfer keys: Succeces");
-        //     }
-        // }
-        while let Some(res) = rx.recv().await {
-            if let Err(e) = res {
-                tracing::error!("Error: {:?}", e);
-                return Err(e);
-            }
+    let mut status =
+        Status::relayed(state.sae_id(), &sae_id, &statuses).ok_or(EtsiServerError::PathError)?;
+    status.status_extension = Some(serde_json::json!({
+        "relay": {
+            "paths": paths,
+            "stored_key_count": state.stored_key_count(&sae_id).await?,
+            "max_key_count": state.max_key_count(),
         }
+    }));
+    Ok(axum::Json(status).into_response())
+}
 
-        tracing::info!("Transfer keys: Succeces");
-        Ok(Response::from_parts(parts, Body::from(body_bytes)))
+/// The local link a SAE hop list leaves this relay through.
+fn first_link<'a>(state: &'a AppStateEtsi, path: &[String]) -> Option<&'a Pqkd> {
+    path.windows(2)
+        .find_map(|hop| state.pqkd(|p| p.sae_id() == hop[0] && p.remote_sae_id() == hop[1]))
+}
+
+async fn kme_status(state: &AppStateEtsi, pqkd: &Pqkd) -> Result<Status, EtsiServerError> {
+    let req = hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/api/v1/keys/{}/status",
+            pqkd.kme_address(),
+            pqkd.remote_sae_id()
+        ))
+        .body(Body::empty())?;
+    let client = state
+        .client_for_sae_id(pqkd.sae_id())
+        .ok_or(EtsiServerError::UnknownPqkd(pqkd.sae_id().to_string()))?;
+    let res = client.request(req).await?;
+    if res.status() != StatusCode::OK {
+        return Err(EtsiServerError::PqkdRequestError(res.status()));
     }
+    let body = axum::body::to_bytes(res.into_response().into_body(), usize::MAX).await?;
+    Ok(serde_json::from_slice(&body)?)
 }
 
 async fn _dec_keys(
@@ -424,40 +895,35 @@ async fn _dec_keys(
         let key_ids: KeyIds = match *req.method() {
             Method::GET => {
                 if let Some(param) = req.uri().query() {
-                    let query: Result<DecKeysQuery, _> = serde_qs::from_str(param);
-                    if query.is_err() {
-                        return Ok(response_json(StatusCode::BAD_REQUEST, "No Key IDs"));
-                    }
-                    let query = query.map_err(|_| EtsiServerError::GetKeysError)?;
+                    let query: DecKeysQuery = serde_qs::from_str(param)
+                        .map_err(|e| EtsiServerError::InvalidRequest(e.to_string()))?;
                     let keyid = KeyId {
                         key_id: query.key_id,
+                        key_id_extension: None,
                     };
                     KeyIds {
                         key_ids: vec![keyid],
                     }
                 } else {
-                    return Ok(response_json(StatusCode::BAD_REQUEST, "No Key IDs"));
+                    return Err(EtsiServerError::InvalidRequest(
+                        "no key_ID given".to_string(),
+                    ));
                 }
             }
             Method::POST => {
                 let body = axum::body::to_bytes(req.into_body(), usize::MAX).await?;
-                let keys_ids: Result<KeyIds, _> = serde_json::from_slice(&body[..]);
-                if keys_ids.is_err() {
-                    return Ok(response_json(StatusCode::BAD_REQUEST, "No Key IDs"));
-                }
-                keys_ids.map_err(EtsiServerError::from)?
+                serde_json::from_slice(&body[..])
+                    .map_err(|e| EtsiServerError::InvalidRequest(e.to_string()))?
             }
             _ => KeyIds { key_ids: vec![] },
         };
         tracing::info!("Key IDs: {:?}", key_ids);
-        let Ok(keys) = state.get_key(&sae_id, &key_ids) else {
-            return Err(EtsiServerError::GetKeysError);
-        };
+        let keys = state.get_key(&sae_id, &key_ids).await?;
         if !keys.keys.is_empty() {
             let body = serde_json::to_string(&keys)?;
             Ok(Response::new(Body::from(body)).into_response())
         } else {
-            Ok(response_json(StatusCode::NOT_FOUND, "No Key IDs"))
+            Err(EtsiServerError::KeysNotFound)
         }
     }
 }
@@ -481,10 +947,40 @@ async fn h(state: AppStateEtsi, mut req: Request) -> Result<Response, EtsiServer
     Ok(state.client().request(req).await?.into_response())
 }
 
+/// `number` fresh keys of `size` bits from the KME of the link `pqkd`.
+async fn link_keys(
+    client: &Client,
+    pqkd: &Pqkd,
+    size: usize,
+    number: usize,
+) -> Result<Vec<Key>, EtsiServerError> {
+    let req = hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/api/v1/keys/{}/enc_keys?size={}&number={}",
+            pqkd.kme_address(),
+            pqkd.remote_sae_id(),
+            size,
+            number
+        ))
+        .body(Body::empty())?;
+    let res = client.request(req).await?.into_response();
+
+    if res.status() != StatusCode::OK {
+        return Err(EtsiServerError::PqkdRequestError(res.status()));
+    }
+
+    let body_bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+
+    let keys: Keys = serde_json::from_slice(&body_bytes[..])?;
+    Ok(keys.keys())
+}
+
 async fn send_keys(
     state: Arc<AppStateEtsi>,
     path: Vec<String>,
-    keys: Arc<Vec<Key>>,
+    share: Share,
+    keys: Vec<Key>,
 ) -> Result<(), EtsiServerError> {
     let first = path.get(1).ok_or(EtsiServerError::PathError)?;
     let pqkd = if let Some(pq) = state.pqkd(|p| p.sae_id() == first) {
@@ -495,9 +991,6 @@ async fn send_keys(
             .ok_or(EtsiServerError::UnknownPqkd(state.sae_id().to_string()))?
     };
 
-    // let pqkd = state
-    //     .pqkd(|p| p.sae_id() == state.sae_id())
-    //     .ok_or(EtsiServerError::UnknownPqkd(state.sae_id().to_string()))?;
     let position = path
         .iter()
         .position(|i| i == pqkd.sae_id())
@@ -510,74 +1003,50 @@ async fn send_keys(
 
     tracing::info!("Send keys to next node {}", pqkd.remote_sae_id());
 
-    let data = if position == 0 {
-        let keys_ids: Vec<String> = keys.iter().map(|k| k.key_id.clone()).collect();
-        let keys_for_send: Vec<Prom> = keys_ids
-            .iter()
-            .map(|k| Prom {
-                key_id: String::from(k),
-                key_id_xor: None,
-                key: None,
-            })
-            .collect();
-        DataKeys {
-            from: String::from(pqkd.sae_id()),
-            to: String::from(pqkd.remote_sae_id()),
-            path,
-            keys: keys_for_send,
-        }
-    } else {
-        let number = keys.len();
-        let first_key = keys.first().ok_or(EtsiServerError::PathError)?;
-        let size = BASE64_STANDARD.decode(first_key.key.clone())?.len() * 8;
-        let req = hyper::Request::builder()
-            .method(hyper::Method::GET)
-            .uri(format!(
-                "{}/api/v1/keys/{}/enc_keys?size={}&number={}",
-                pqkd.kme_address(),
-                pqkd.remote_sae_id(),
-                size,
-                number
-            ))
-            .body(Body::empty())?;
-        let client = state
-            .client_for_sae_id(pqkd.sae_id())
-            .ok_or(EtsiServerError::UnknownPqkd(pqkd.sae_id().to_string()))?;
-        let res = client.request(req).await?.into_response();
-
-        if res.status() != StatusCode::OK {
-            return Err(EtsiServerError::PqkdRequestError(res.status()));
-        }
-
-        let body_bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
-
-        let keys_for_xor: Keys = serde_json::from_slice(&body_bytes[..])?;
-
-        let keys_for_xor = keys_for_xor.keys();
-        if keys_for_xor.len() != keys.len() {
-            return Err(EtsiServerError::SendKeysError);
-        }
-
-        let mut keys_for_send = Vec::new();
+    // Every share leaves masked with a fresh key of the outgoing link.
+    let number = keys.len();
+    let first_key = keys.first().ok_or(EtsiServerError::PathError)?;
+    let size = BASE64_STANDARD.decode(first_key.key.clone())?.len() * 8;
+    let client = state
+        .client_for_sae_id(pqkd.sae_id())
+        .ok_or(EtsiServerError::UnknownPqkd(pqkd.sae_id().to_string()))?;
+    let keys_for_xor = link_keys(client, pqkd, size, number).await?;
+    if keys_for_xor.len() != keys.len() {
+        return Err(EtsiServerError::SendKeysError);
+    }
 
-        for i in 0..keys.len() {
-            keys_for_send.push(Prom {
-                key_id: keys[i].key_id.clone(),
-                key_id_xor: Some(keys_for_xor[i].key_id.clone()),
-                key: Some(util::xor(
-                    keys[i].key.as_bytes().to_vec(),
-                    keys_for_xor[i].key.as_bytes().to_vec(),
-                )),
-            });
-        }
+    let mut keys_for_send = Vec::new();
+
+    for i in 0..keys.len() {
+        keys_for_send.push(Prom {
+            key_id: keys[i].key_id.clone(),
+            key_id_xor: Some(keys_for_xor[i].key_id.clone()),
+            key: Some(util::xor(
+                keys[i].key.as_bytes().to_vec(),
+                keys_for_xor[i].key.as_bytes().to_vec(),
+            )),
+            extensions: keys[i].extensions.clone(),
+        });
+    }
 
-        DataKeys {
-            from: String::from(pqkd.sae_id()),
-            to: String::from(pqkd.remote_sae_id()),
-            path,
-            keys: keys_for_send,
-        }
-    };
+    let mut data = DataKeys::new(
+        String::from(pqkd.sae_id()),
+        String::from(pqkd.remote_sae_id()),
+        path,
+        share,
+        keys_for_send,
+    );
+    if let Some(attestor) = state.hops().attestor() {
+        attestor.attest(&mut data)?;
+    }
+    // One more link key authenticates the masked shares to the next relay.
+    let mac_key = link_keys(client, pqkd, util::MAC_KEY_LEN * 8, 1)
+        .await?
+        .pop()
+        .ok_or(EtsiServerError::SendKeysError)?;
+    if !data.seal(mac_key.key_id, &BASE64_STANDARD.decode(mac_key.key)?)? {
+        return Err(EtsiServerError::SendKeysError);
+    }
     let request = hyper::Request::builder()
         .method(hyper::Method::POST)
         .uri(format!("{}/info_keys", pqkd.remote_proxy_address()))
@@ -586,39 +1055,140 @@ async fn send_keys(
             serde_json::to_string(&data).map_err(|_| EtsiServerError::SendKeysError)?,
         ))?;
 
-    let res = state.client().request(request).await?.into_response();
+    let res = state
+        .hops()
+        .client()
+        .request(request)
+        .await?
+        .into_response();
 
     if res.status() != StatusCode::OK {
-        return Err(EtsiServerError::SendKeysError);
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        return Err(EtsiServerError::RelayRequestError(
+            status,
+            serde_json::from_slice(&body).ok(),
+        ));
     }
 
     Ok(())
 }
 
-fn response_json(status: StatusCode, message: &str) -> Response {
-    let body = format!("{{\"error\":\"{}\"}}", message);
-    let mut response = Response::new(Body::from(body));
-    *response.status_mut() = status;
-    response.headers_mut().insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("application/json"),
-    );
-    response
-}
+#[cfg(test)]
+mod tests {
+    use super::{DataKeys, EncKeysRequest, Prom, Share, Status};
+    use crate::util::MAC_KEY_LEN;
+
+    fn link(kme: &str, stored: u64, max_per_request: u64, sizes: (u64, u64)) -> Status {
+        Status {
+            source_kme_id: kme.to_string(),
+            target_kme_id: Some(format!("{}_peer", kme)),
+            master_sae_id: "A".to_string(),
+            slave_sae_id: "B".to_string(),
+            key_size: 256,
+            stored_key_count: stored,
+            max_key_count: 1000,
+            max_key_per_request: max_per_request,
+            max_key_size: sizes.1,
+            min_key_size: sizes.0,
+            max_sae_id_count: 0,
+            status_extension: None,
+        }
+    }
 
-fn error_response(err: EtsiServerError) -> Response {
-    let status = match err {
-        EtsiServerError::PqkdRequestError(code) => code,
-        EtsiServerError::UnknownPqkd(_) => StatusCode::BAD_REQUEST,
-        EtsiServerError::PathError => StatusCode::BAD_REQUEST,
-        EtsiServerError::SendKeysError => StatusCode::BAD_GATEWAY,
-        EtsiServerError::GetKeysError => StatusCode::BAD_REQUEST,
-        _ => StatusCode::INTERNAL_SERVER_ERROR,
-    };
-    let message = if status.is_server_error() {
-        "failed"
-    } else {
-        "invalid_request"
-    };
-    response_json(status, message)
+    #[test]
+    fn relayed_status_is_bounded_by_the_tightest_link() {
+        let links = [
+            (link("KME_A", 500, 128, (64, 1024)), 1),
+            // Two paths leave through this link, so it halves its counts.
+            (link("KME_B", 300, 256, (128, 4096)), 2),
+        ];
+        let status = Status::relayed("Alice", "Carol", &links).expect("status");
+
+        assert_eq!(status.source_kme_id, "KME_A");
+        assert_eq!(status.target_kme_id, None);
+        assert_eq!(status.master_sae_id, "Alice");
+        assert_eq!(status.slave_sae_id, "Carol");
+        assert_eq!(status.key_size, 256);
+        assert_eq!(status.stored_key_count, 150);
+        assert_eq!(status.max_key_count, 500);
+        assert_eq!(status.max_key_per_request, 128);
+        assert_eq!((status.min_key_size, status.max_key_size), (128, 1024));
+        assert_eq!(Status::relayed("Alice", "Carol", &[]), None);
+
+        let json = serde_json::to_value(&status).expect("json");
+        assert_eq!(json["source_KME_ID"], "KME_A");
+        assert_eq!(json["max_SAE_ID_count"], 8);
+        assert!(json.get("target_KME_ID").is_none());
+        assert!(json.get("status_extension").is_none());
+    }
+
+    #[test]
+    fn enc_keys_request_parses_etsi_post_options() {
+        let request: EncKeysRequest = serde_json::from_str(
+            r#"{
+                "number": 2,
+                "size": 256,
+                "additional_slave_SAE_IDs": ["Carol", "Bob", "Carol"],
+                "extension_mandatory": [{"abc_route_type": "direct"}],
+                "extension_optional": [{"abc_transfer_method": "qkd"}]
+            }"#,
+        )
+        .expect("valid request");
+
+        assert_eq!(request.slaves("Bob"), vec!["Bob", "Carol"]);
+        assert_eq!(request.unsupported_extensions(), vec!["abc_route_type"]);
+        assert_eq!(request.kme_query(), "number=2&size=256");
+
+        let get: EncKeysRequest = serde_qs::from_str("size=512").expect("valid query");
+        assert_eq!(get.slaves("Bob"), vec!["Bob"]);
+        assert!(get.unsupported_extensions().is_empty());
+        assert_eq!(get.kme_query(), "size=512");
+    }
+
+    #[test]
+    fn hop_mac_rejects_tampered_shares() {
+        let share = Share {
+            index: 0,
+            total: 2,
+            threshold: None,
+        };
+        let prom = |key: &[u8]| {
+            Prom::new(
+                "k1".to_string(),
+                Some("x1".to_string()),
+                Some(key.to_vec()),
+                Default::default(),
+            )
+        };
+        let path = vec!["A".to_string(), "B".to_string()];
+        let message = |key: &[u8]| {
+            DataKeys::new(
+                "A".to_string(),
+                "B".to_string(),
+                path.clone(),
+                share,
+                vec![prom(key)],
+            )
+        };
+        let link_key = [3u8; MAC_KEY_LEN];
+
+        let mut sealed = message(b"masked");
+        assert!(!sealed.verify(&link_key));
+        assert!(sealed.seal("m1".to_string(), &link_key).expect("seal"));
+        assert!(!sealed.seal("m1".to_string(), &link_key[1..]).expect("seal"));
+
+        // The tag survives the trip to the next relay.
+        let received: DataKeys =
+            serde_json::from_str(&serde_json::to_string(&sealed).expect("json")).expect("parse");
+        assert_eq!(received.mac().map(|m| m.key_id.as_str()), Some("m1"));
+        assert!(received.verify(&link_key));
+        assert!(!received.verify(&[4u8; MAC_KEY_LEN]));
+
+        // A flipped bit in a masked share no longer matches the tag.
+        let mut tampered = serde_json::to_value(&sealed).expect("json");
+        tampered["keys"][0]["key"][0] = serde_json::json!(b'm' ^ 1);
+        let tampered: DataKeys = serde_json::from_value(tampered).expect("parse");
+        assert!(!tampered.verify(&link_key));
+    }
 }
@@ -1,37 +1,141 @@
 use crate::config::{Config, Hypercube, Pqkd};
-use crate::etsi_server::{Key, KeyIds, Keys};
+use crate::etsi_server::{Key, KeyExtensions, KeyIds, Keys, Share};
+use crate::health::LinkHealth;
+use crate::relay_server::{Hops, RelayServerError};
+use crate::store::KeyStore;
+use crate::util;
 use axum::body::Body;
+use base64::prelude::*;
 use hyper_tls::HttpsConnector;
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use std::{
-    fs,
-    sync::{Arc, Mutex},
-};
+use serde::{Deserialize, Serialize};
+use std::{fs, sync::Arc, time::Duration};
+use zeroize::{Zeroize, Zeroizing};
 
 use super::error::EtsiServerError;
 
 use std::collections::HashMap;
 pub type Client = hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Body>;
 
+/// Most shares one key can be split into.
+const MAX_SHARES: usize = 255;
+
+/// Shares of one relayed key, collected until enough paths have delivered.
+#[derive(Serialize, Deserialize)]
 pub struct KeyReceived {
-    pub num: u8,
     pub from: String,
     pub key_id: String,
-    pub key: String,
+    pub total: usize,
+    pub threshold: Option<usize>,
+    pub shares: Vec<(usize, String)>,
+    /// Arrival of the first share, in milliseconds since the Unix epoch.
+    /// Records written before it existed read as 0 and are stamped when the
+    /// key store is opened.
+    #[serde(default)]
+    pub received_at: u64,
+    /// Extensions the first share arrived with.
+    #[serde(default)]
+    pub extensions: KeyExtensions,
 }
 
 impl KeyReceived {
-    pub fn new(from: String, key_id: String, key: String) -> Self {
+    pub fn new(from: String, key_id: String, share: Share) -> Self {
         Self {
-            num: 1u8,
             from,
             key_id,
-            key,
+            total: share.total,
+            threshold: share.threshold,
+            shares: Vec::new(),
+            received_at: util::unix_millis(),
+            extensions: KeyExtensions::default(),
+        }
+    }
+
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        u128::from(util::unix_millis().saturating_sub(self.received_at)) >= ttl.as_millis()
+    }
+
+    pub fn add_share(
+        &mut self,
+        share: Share,
+        mut key: Zeroizing<String>,
+    ) -> Result<(), RelayServerError> {
+        // Shamir shares are evaluated at x = index + 1 in GF(256), so at most
+        // 255 of them exist and x = 0, the secret, is never handed out.
+        if share.total != self.total
+            || share.threshold != self.threshold
+            || share.index >= self.total
+            || self.total > MAX_SHARES
+            || self.threshold.is_some_and(|k| k == 0 || k > self.total)
+        {
+            return Err(RelayServerError::InvalidShare);
+        }
+        match self.shares.iter().find(|(i, _)| *i == share.index) {
+            Some((_, k)) if *k == *key => Ok(()),
+            Some(_) => Err(RelayServerError::KeysDoNotMaych),
+            None => {
+                // Moved rather than copied, so the only buffer is the one
+                // zeroized on drop.
+                self.shares.push((share.index, std::mem::take(&mut *key)));
+                Ok(())
+            }
         }
     }
 
-    pub fn num(&mut self) {
-        self.num += 1;
+    pub fn is_complete(&self) -> bool {
+        !self.shares.is_empty() && self.shares.len() >= self.threshold.unwrap_or(self.total)
+    }
+
+    /// The key rebuilt from the shares, `None` while too few have arrived or
+    /// when the Shamir shares beyond the threshold disagree with the others.
+    /// Every buffer holding key material on the way is zeroized.
+    pub fn key(&self) -> Option<Zeroizing<String>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut shares = Zeroizing::new(Vec::new());
+        for (index, share) in self.shares.iter() {
+            let x = u8::try_from(*index + 1).ok()?;
+            shares.push((x, BASE64_STANDARD.decode(share).ok()?));
+        }
+        let key = match self.threshold {
+            Some(k) => {
+                let (points, rest) = shares.split_at(k);
+                for (x, y) in rest {
+                    if *Zeroizing::new(util::shamir_interpolate(points, *x)) != *y {
+                        return None;
+                    }
+                }
+                Zeroizing::new(util::shamir_interpolate(points, 0))
+            }
+            None => {
+                let (_, first) = shares.first()?;
+                let mut key = Zeroizing::new(first.clone());
+                for (_, share) in &shares[1..] {
+                    key.truncate(share.len());
+                    key.iter_mut().zip(share).for_each(|(k, s)| *k ^= s);
+                }
+                key
+            }
+        };
+        Some(Zeroizing::new(BASE64_STANDARD.encode(&*key)))
+    }
+
+    /// The rebuilt key with its ID and extensions, as handed to the SAE.
+    pub fn rebuilt(&self) -> Option<Key> {
+        Some(Key {
+            key: self.key()?,
+            key_id: self.key_id.clone(),
+            extensions: self.extensions.clone(),
+        })
+    }
+}
+
+impl Drop for KeyReceived {
+    fn drop(&mut self) {
+        for (_, share) in self.shares.iter_mut() {
+            share.zeroize();
+        }
     }
 }
 
@@ -40,25 +144,31 @@ pub struct AppStateEtsi {
     id_relay: String,
     sae_id: String,
     pqkds: Vec<Pqkd>,
-    keys: Arc<Mutex<Vec<KeyReceived>>>,
+    keys: Arc<dyn KeyStore>,
+    /// Most relayed keys held from one origin SAE.
+    max_key_count: usize,
     client: Arc<Client>,
     clients: Arc<HashMap<String, Arc<Client>>>,
+    hops: Arc<Hops>,
     hypercube: Arc<Hypercube>,
+    health: LinkHealth,
 }
 
 impl AppStateEtsi {
     pub fn build(
         local_sae_id: &str,
         config: &Config,
-        keys: Arc<Mutex<Vec<KeyReceived>>>,
+        keys: Arc<dyn KeyStore>,
         clients: Arc<HashMap<String, Arc<Client>>>,
+        hops: Arc<Hops>,
         hypercube: Arc<Hypercube>,
+        health: LinkHealth,
     ) -> Result<AppStateEtsi, EtsiServerError> {
         let pqkd = config
             .pqkds()
             .iter()
             .find(|p| p.sae_id() == local_sae_id)
-            .unwrap();
+            .ok_or(EtsiServerError::UnknownPqkd(local_sae_id.to_string()))?;
         let client = if let (Some(ca_cert), Some(client_cert), Some(client_key)) =
             (pqkd.ca_cert(), pqkd.client_cert(), pqkd.client_key())
         {
@@ -98,9 +208,12 @@ impl AppStateEtsi {
             sae_id: String::from(local_sae_id),
             pqkds: config.pqkds().clone(),
             keys,
+            max_key_count: config.max_keys().min(config.max_keys_per_origin()),
             client: Arc::new(client),
             clients,
+            hops,
             hypercube,
+            health,
         })
     }
 
@@ -136,28 +249,61 @@ impl AppStateEtsi {
         self.clients.get(sae_id)
     }
 
+    pub fn hops(&self) -> &Hops {
+        &self.hops
+    }
+
     pub fn hypercube(&self) -> &Arc<Hypercube> {
         &self.hypercube
     }
 
-    pub fn get_key(&self, from: &str, key_ids: &KeyIds) -> Result<Keys, EtsiServerError> {
-        let mut keys = self
-            .keys
-            .lock()
-            .map_err(|_| EtsiServerError::GetKeysError)?;
-        let mut return_keys = Vec::new();
-        for key_id in &key_ids.key_ids {
-            if let Some(p) = keys
-                .iter()
-                .position(|k| k.from == from && k.key_id == key_id.key_id && k.num == 2)
-            {
-                let key = keys.swap_remove(p);
-                return_keys.push(Key {
-                    key: key.key,
-                    key_id: key.key_id,
-                });
-            };
-        }
+    pub fn health(&self) -> &LinkHealth {
+        &self.health
+    }
+
+    /// Relayed keys from `from` waiting to be fetched.
+    pub async fn stored_key_count(&self, from: &str) -> Result<usize, EtsiServerError> {
+        let (keys, from) = (Arc::clone(&self.keys), from.to_string());
+        let count = tokio::task::spawn_blocking(move || keys.count(Some(&from)))
+            .await
+            .map_err(|e| {
+                tracing::error!("Key store task failed: {}", e);
+                EtsiServerError::GetKeysError
+            })?;
+        count.map_err(|e| {
+            tracing::error!("Key store error: {}", e);
+            EtsiServerError::GetKeysError
+        })
+    }
+
+    pub fn max_key_count(&self) -> usize {
+        self.max_key_count
+    }
+
+    /// Takes the requested keys on a blocking thread, as the durable store
+    /// commits every removal to disk before returning.
+    pub async fn get_key(&self, from: &str, key_ids: &KeyIds) -> Result<Keys, EtsiServerError> {
+        let keys = Arc::clone(&self.keys);
+        let from = from.to_string();
+        let key_ids: Vec<String> = key_ids.key_ids.iter().map(|k| k.key_id.clone()).collect();
+        let taken = tokio::task::spawn_blocking(move || {
+            let mut return_keys = Vec::new();
+            for key_id in key_ids.iter() {
+                if let Some(key) = keys.take_key(&from, key_id)? {
+                    return_keys.push(key);
+                }
+            }
+            Ok::<_, crate::store::StoreError>(return_keys)
+        })
+        .await
+        .map_err(|e| {
+            tracing::error!("Key store task failed: {}", e);
+            EtsiServerError::GetKeysError
+        })?;
+        let return_keys = taken.map_err(|e| {
+            tracing::error!("Key store error: {}", e);
+            EtsiServerError::GetKeysError
+        })?;
 
         Ok(Keys { keys: return_keys })
     }
@@ -167,11 +313,17 @@ impl AppStateEtsi {
 mod tests {
     use super::{AppStateEtsi, Client, KeyReceived};
     use crate::config::Hypercube;
-    use crate::etsi_server::{server::KeyId, KeyIds};
+    use crate::etsi_server::{server::KeyId, Key, KeyExtensions, KeyIds, Share};
+    use crate::health::LinkHealth;
+    use crate::relay_server::{Hops, RelayServerError};
+    use crate::store::{KeyStore, MemoryStore};
+    use crate::tls;
+    use crate::util;
+    use base64::prelude::*;
     use hyper_tls::HttpsConnector;
     use hyper_util::rt::TokioExecutor;
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::Arc;
 
     fn test_hypercube() -> Arc<Hypercube> {
         let toml = r#"
@@ -198,67 +350,281 @@ second = "Bob"
         Arc::new(client)
     }
 
-    #[test]
-    fn get_key_returns_only_entries_with_num_equal_two() {
-        let keys = Arc::new(Mutex::new(vec![
-            KeyReceived {
-                num: 2,
-                from: "Relay_00".to_string(),
-                key_id: "k1".to_string(),
-                key: "v1".to_string(),
-            },
-            KeyReceived {
-                num: 1,
-                from: "Relay_00".to_string(),
-                key_id: "k2".to_string(),
-                key: "v2".to_string(),
+    #[tokio::test]
+    async fn get_key_returns_only_entries_with_every_share() {
+        let share = |index| Share {
+            index,
+            total: 2,
+            threshold: None,
+        };
+        let keys: Arc<dyn KeyStore> = Arc::new(MemoryStore::default());
+        // "AQI=" ^ "AwA=" == [1, 2] ^ [3, 0] == [2, 2] == "AgI="
+        let key = |key_id: &str, key: &str| Key {
+            key: key.to_string().into(),
+            key_id: key_id.to_string(),
+            extensions: KeyExtensions {
+                key_id_extension: Some(serde_json::json!({ "vendor": key_id })),
+                key_extension: None,
             },
-        ]));
+        };
+        for (key_id, index, k) in [("k1", 0, "AQI="), ("k1", 1, "AwA="), ("k2", 1, "AwA=")] {
+            keys.add_share("Relay_00".into(), share(index), key(key_id, k))
+                .expect("add share");
+        }
 
         let state = AppStateEtsi {
             id_relay: "00".to_string(),
             sae_id: "Alice".to_string(),
             pqkds: vec![],
             keys: Arc::clone(&keys),
+            max_key_count: 10,
             client: test_client(),
             clients: Arc::new(HashMap::new()),
+            hops: Arc::new(Hops::new(
+                tls::relay_client(None).expect("relay client"),
+                None,
+            )),
             hypercube: test_hypercube(),
+            health: LinkHealth::default(),
         };
 
         let key_ids = KeyIds {
             key_ids: vec![KeyId {
                 key_id: "k1".to_string(),
+                key_id_extension: None,
             }],
         };
 
-        let response = state.get_key("Relay_00", &key_ids).expect("get_key ok");
+        let response = state
+            .get_key("Relay_00", &key_ids)
+            .await
+            .expect("get_key ok");
         assert_eq!(response.keys.len(), 1);
         assert_eq!(response.keys[0].key_id, "k1");
-        assert_eq!(response.keys[0].key, "v1");
-
-        let store = keys.lock().expect("lock keys");
-        assert_eq!(store.len(), 1);
-        assert_eq!(store[0].key_id, "k2");
+        assert_eq!(*response.keys[0].key, "AgI=");
+        assert_eq!(
+            serde_json::to_value(&response.keys[0]).expect("json"),
+            serde_json::json!({
+                "key_ID": "k1",
+                "key_ID_extension": { "vendor": "k1" },
+                "key": "AgI="
+            })
+        );
+
+        // k2 is still waiting for its second share.
+        keys.add_share("Relay_00".into(), share(0), key("k2", "AQI="))
+            .expect("add share");
+        assert_eq!(
+            keys.take_key("Relay_00", "k2")
+                .expect("take key")
+                .map(|k| k.key),
+            Some("AgI=".to_string().into())
+        );
     }
 
-    #[test]
-    fn get_key_returns_empty_when_no_matching_items() {
+    #[tokio::test]
+    async fn get_key_returns_empty_when_no_matching_items() {
         let state = AppStateEtsi {
             id_relay: "00".to_string(),
             sae_id: "Alice".to_string(),
             pqkds: vec![],
-            keys: Arc::new(Mutex::new(Vec::new())),
+            keys: Arc::new(MemoryStore::default()),
+            max_key_count: 10,
             client: test_client(),
             clients: Arc::new(HashMap::new()),
+            hops: Arc::new(Hops::new(
+                tls::relay_client(None).expect("relay client"),
+                None,
+            )),
             hypercube: test_hypercube(),
+            health: LinkHealth::default(),
         };
         let key_ids = KeyIds {
             key_ids: vec![KeyId {
                 key_id: "unknown".to_string(),
+                key_id_extension: None,
             }],
         };
 
-        let response = state.get_key("Relay_00", &key_ids).expect("get_key ok");
+        let response = state
+            .get_key("Relay_00", &key_ids)
+            .await
+            .expect("get_key ok");
         assert!(response.keys.is_empty());
     }
+
+    #[test]
+    fn key_received_rebuilds_key_once_every_share_is_present() {
+        let mut received = KeyReceived::new(
+            "Alice".to_string(),
+            "k1".to_string(),
+            Share {
+                index: 0,
+                total: 3,
+                threshold: None,
+            },
+        );
+
+        received
+            .add_share(
+                Share {
+                    index: 2,
+                    total: 3,
+                    threshold: None,
+                },
+                "BwA=".to_string().into(),
+            )
+            .expect("share 2");
+        received
+            .add_share(
+                Share {
+                    index: 0,
+                    total: 3,
+                    threshold: None,
+                },
+                "AQI=".to_string().into(),
+            )
+            .expect("share 0");
+        assert!(!received.is_complete());
+        assert_eq!(received.key(), None);
+
+        received
+            .add_share(
+                Share {
+                    index: 1,
+                    total: 3,
+                    threshold: None,
+                },
+                "AwA=".to_string().into(),
+            )
+            .expect("share 1");
+        assert!(received.is_complete());
+        // [1, 2] ^ [3, 0] ^ [7, 0] == [5, 2]
+        assert_eq!(received.key(), Some("BQI=".to_string().into()));
+    }
+
+    #[test]
+    fn key_received_rejects_conflicting_or_out_of_range_shares() {
+        let mut received = KeyReceived::new(
+            "Alice".to_string(),
+            "k1".to_string(),
+            Share {
+                index: 0,
+                total: 2,
+                threshold: None,
+            },
+        );
+        received
+            .add_share(
+                Share {
+                    index: 0,
+                    total: 2,
+                    threshold: None,
+                },
+                "AQI=".to_string().into(),
+            )
+            .expect("first share");
+
+        received
+            .add_share(
+                Share {
+                    index: 0,
+                    total: 2,
+                    threshold: None,
+                },
+                "AQI=".to_string().into(),
+            )
+            .expect("same share again is ignored");
+        assert_eq!(received.shares.len(), 1);
+
+        assert!(matches!(
+            received.add_share(
+                Share {
+                    index: 0,
+                    total: 2,
+                    threshold: None
+                },
+                "AwA=".to_string().into()
+            ),
+            Err(RelayServerError::KeysDoNotMaych)
+        ));
+        assert!(matches!(
+            received.add_share(
+                Share {
+                    index: 2,
+                    total: 2,
+                    threshold: None
+                },
+                "AwA=".to_string().into()
+            ),
+            Err(RelayServerError::InvalidShare)
+        ));
+        assert!(matches!(
+            received.add_share(
+                Share {
+                    index: 1,
+                    total: 3,
+                    threshold: None
+                },
+                "AwA=".to_string().into()
+            ),
+            Err(RelayServerError::InvalidShare)
+        ));
+    }
+
+    #[test]
+    fn key_received_rejects_share_counts_outside_gf256() {
+        let share = |index: usize, total: usize, threshold: Option<usize>| Share {
+            index,
+            total,
+            threshold,
+        };
+        for bad in [
+            share(255, 256, Some(2)),
+            share(256, 300, None),
+            share(0, 3, Some(0)),
+            share(0, 3, Some(4)),
+        ] {
+            let mut received = KeyReceived::new("Alice".to_string(), "k1".to_string(), bad);
+            assert!(matches!(
+                received.add_share(bad, "AQI=".to_string().into()),
+                Err(RelayServerError::InvalidShare)
+            ));
+            assert!(!received.is_complete());
+        }
+
+        let last = share(254, 255, Some(2));
+        let mut received = KeyReceived::new("Alice".to_string(), "k1".to_string(), last);
+        received
+            .add_share(last, "AQI=".to_string().into())
+            .expect("x = 255 is a valid point");
+    }
+
+    #[test]
+    fn key_received_rebuilds_shamir_key_from_threshold_shares() {
+        let secret = b"relayed".to_vec();
+        let shares = util::shamir_split(&secret, 2, 3);
+        let share = |index: usize| Share {
+            index,
+            total: 3,
+            threshold: Some(2),
+        };
+
+        let mut received = KeyReceived::new("Alice".to_string(), "k1".to_string(), share(0));
+        received
+            .add_share(share(2), BASE64_STANDARD.encode(&shares[2]).into())
+            .expect("share 2");
+        assert_eq!(received.key(), None);
+
+        received
+            .add_share(share(0), BASE64_STANDARD.encode(&shares[0]).into())
+            .expect("share 0");
+        assert_eq!(received.key(), Some(BASE64_STANDARD.encode(&secret).into()));
+
+        // A third share that does not lie on the same polynomial is caught.
+        received
+            .add_share(share(1), BASE64_STANDARD.encode(b"corrupt").into())
+            .expect("share 1");
+        assert_eq!(received.key(), None);
+    }
 }
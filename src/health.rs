@@ -0,0 +1,180 @@
+use crate::config::Pqkd;
+use crate::etsi_server::Client;
+use axum::body::Body;
+use axum::response::IntoResponse;
+use hyper::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Last known state of a QKD link, as reported by the KME `status` endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Up {
+        stored_key_count: u64,
+        max_key_count: u64,
+    },
+    Down,
+}
+
+impl LinkState {
+    /// A link is usable while its KME answers and still has keys in store.
+    pub fn is_usable(&self) -> bool {
+        matches!(self, LinkState::Up { stored_key_count, .. } if *stored_key_count > 0)
+    }
+
+    /// Share of the key buffer that is used up, in `[0, 1]`.
+    pub fn depletion(&self) -> f64 {
+        match self {
+            LinkState::Up {
+                stored_key_count,
+                max_key_count,
+            } if *max_key_count > 0 => {
+                1.0 - (*stored_key_count as f64 / *max_key_count as f64).min(1.0)
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+/// Live link-health table shared between the status poller and path selection.
+/// Links that were never polled are absent and treated as healthy.
+#[derive(Clone, Default, Debug)]
+pub struct LinkHealth {
+    links: Arc<RwLock<HashMap<(String, String), LinkState>>>,
+}
+
+impl LinkHealth {
+    pub fn update(&self, sae_id: &str, remote_sae_id: &str, state: LinkState) {
+        if let Ok(mut links) = self.links.write() {
+            links.insert((sae_id.to_string(), remote_sae_id.to_string()), state);
+        }
+    }
+
+    /// State of the link between two SAEs, in either direction.
+    pub fn get(&self, a: &str, b: &str) -> Option<LinkState> {
+        let links = self.links.read().ok()?;
+        links
+            .get(&(a.to_string(), b.to_string()))
+            .or_else(|| links.get(&(b.to_string(), a.to_string())))
+            .copied()
+    }
+}
+
+#[derive(Deserialize)]
+struct Status {
+    stored_key_count: u64,
+    max_key_count: u64,
+}
+
+async fn poll_status(pqkd: &Pqkd, client: &Client) -> LinkState {
+    let req = match hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/api/v1/keys/{}/status",
+            pqkd.kme_address(),
+            pqkd.remote_sae_id()
+        ))
+        .body(Body::empty())
+    {
+        Ok(req) => req,
+        Err(_) => return LinkState::Down,
+    };
+    let Ok(res) = client.request(req).await else {
+        return LinkState::Down;
+    };
+    let res = res.into_response();
+    if res.status() != StatusCode::OK {
+        return LinkState::Down;
+    }
+    let Ok(body) = axum::body::to_bytes(res.into_body(), usize::MAX).await else {
+        return LinkState::Down;
+    };
+    match serde_json::from_slice::<Status>(&body[..]) {
+        Ok(status) => LinkState::Up {
+            stored_key_count: status.stored_key_count,
+            max_key_count: status.max_key_count,
+        },
+        Err(_) => LinkState::Down,
+    }
+}
+
+/// Polls the `status` of every configured PQKD link forever. Links are polled
+/// concurrently, and a KME that does not answer within `interval` counts as
+/// down instead of holding up the other links.
+pub async fn poll(
+    pqkds: Vec<Pqkd>,
+    clients: Arc<HashMap<String, Arc<Client>>>,
+    health: LinkHealth,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let mut polls = tokio::task::JoinSet::new();
+        for pqkd in pqkds.iter() {
+            let Some(client) = clients.get(pqkd.sae_id()).cloned() else {
+                continue;
+            };
+            let pqkd = pqkd.clone();
+            polls.spawn(async move {
+                let state = tokio::time::timeout(interval, poll_status(&pqkd, &client))
+                    .await
+                    .unwrap_or(LinkState::Down);
+                (pqkd, state)
+            });
+        }
+        while let Some(polled) = polls.join_next().await {
+            let Ok((pqkd, state)) = polled else {
+                continue;
+            };
+            if !state.is_usable() {
+                tracing::warn!(
+                    "Link {} - {} is not usable: {:?}",
+                    pqkd.sae_id(),
+                    pqkd.remote_sae_id(),
+                    state
+                );
+            }
+            health.update(pqkd.sae_id(), pqkd.remote_sae_id(), state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkHealth, LinkState};
+
+    #[test]
+    fn link_health_lookup_ignores_direction() {
+        let health = LinkHealth::default();
+        health.update("Alice", "Bob", LinkState::Down);
+
+        assert_eq!(health.get("Bob", "Alice"), Some(LinkState::Down));
+        assert_eq!(health.get("Alice", "Carol"), None);
+    }
+
+    #[test]
+    fn link_state_usability_and_depletion() {
+        let full = LinkState::Up {
+            stored_key_count: 100,
+            max_key_count: 100,
+        };
+        let low = LinkState::Up {
+            stored_key_count: 25,
+            max_key_count: 100,
+        };
+        let empty = LinkState::Up {
+            stored_key_count: 0,
+            max_key_count: 100,
+        };
+
+        assert!(full.is_usable());
+        assert_eq!(full.depletion(), 0.0);
+        assert_eq!(low.depletion(), 0.75);
+        assert!(!empty.is_usable());
+        assert!(!LinkState::Down.is_usable());
+    }
+}
@@ -0,0 +1,11 @@
+pub mod attestation;
+pub mod cli;
+pub mod config;
+pub mod etsi_server;
+pub mod health;
+pub mod preview;
+pub mod relay_server;
+pub mod store;
+pub mod tls;
+pub mod topology;
+pub mod util;
@@ -1,14 +1,19 @@
-use std::collections::HashMap;
-use std::env;
-use std::sync::{Arc, Mutex};
-mod cli;
-mod config;
-mod etsi_server;
-mod relay_server;
-mod util;
+use attestation::Attestor;
+use cli::Command;
 use config::{Config, Hypercube};
 use etsi_server::{AppStateEtsi, EtsiServer};
-use relay_server::{AppStateRelay, RelayServer};
+use health::LinkHealth;
+use pqkd_relay::{
+    attestation, cli, config, etsi_server, health, preview, relay_server, store, tls, topology,
+};
+use preview::Preview;
+use relay_server::{AppStateRelay, Hops, RelayServer};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use store::{FileStore, KeyStore, Limits, MemoryStore, RecordCipher};
+use topology::Topology;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use axum::body::Body;
@@ -38,6 +43,54 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::build(args.config_file)?;
     let hypercube = Arc::new(Hypercube::build(args.hypercube_file)?);
 
+    match args.command {
+        Some(Command::Validate) => {
+            return match config::validate(&config, &hypercube) {
+                Ok(()) => {
+                    println!("Configuration is valid");
+                    Ok(())
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+        }
+        Some(Command::Export { format, from, to }) => {
+            let route = from.as_deref().zip(to.as_deref());
+            let topology = Topology::build(&hypercube, route, &LinkHealth::default());
+            print!("{}", topology.render(format));
+            return Ok(());
+        }
+        Some(Command::Preview { from, to }) => {
+            let preview = Preview::build(
+                &hypercube,
+                config.pqkds(),
+                &from,
+                &to,
+                &LinkHealth::default(),
+            );
+            println!("{}", serde_json::to_string_pretty(&preview)?);
+            return Ok(());
+        }
+        Some(Command::Keygen) => {
+            let keys = config
+                .attestation()
+                .ok_or("the config has no [attestation] section")?;
+            println!("{}", attestation::generate(keys.key())?);
+            return Ok(());
+        }
+        None => {}
+    }
+    config::validate(&config, &hypercube)?;
+
+    tracing::info!(
+        "Topology: dimension {}, {} relays, {} connections",
+        hypercube.dimension(),
+        hypercube.relay().len(),
+        hypercube.connection().len()
+    );
+
     let mut list_handles = Vec::new();
 
     let mut keys_map = HashMap::new();
@@ -82,16 +135,67 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let clients_map = Arc::new(clients_map);
+    let attestor = config
+        .attestation()
+        .map(|keys| Attestor::build(config.id(), keys))
+        .transpose()?;
+    if attestor.is_none() {
+        tracing::warn!("No [attestation] configured: relayed paths are not signed or checked");
+    }
+    let hops = Arc::new(
+        Hops::new(tls::relay_client(config.tls())?, attestor)
+            .with_replay_window(config.replay_window()),
+    );
+
+    let health = LinkHealth::default();
+    tokio::task::spawn(health::poll(
+        config.pqkds().clone(),
+        Arc::clone(&clients_map),
+        health.clone(),
+        config.status_interval(),
+    ));
+
+    let file_store = match config.key_store() {
+        Some(path) => {
+            let cipher = config
+                .key_store_encryption()
+                .map(RecordCipher::build)
+                .transpose()?;
+            Some(FileStore::open(path, cipher)?)
+        }
+        None => None,
+    };
+    if let Some(path) = config.key_store() {
+        tracing::info!("Relayed keys are persisted in {}", path.display());
+    }
 
+    let limits = Limits {
+        max_keys: config.max_keys(),
+        max_keys_per_origin: config.max_keys_per_origin(),
+    };
     for pqkd in config.pqkds() {
-        let keys = Arc::new(Mutex::new(Vec::new()));
+        let keys: Arc<dyn KeyStore> = match &file_store {
+            Some(store) => Arc::new(
+                store
+                    .for_sae(pqkd.sae_id())
+                    .with_ttl(config.key_ttl())
+                    .with_limits(limits),
+            ),
+            None => Arc::new(
+                MemoryStore::default()
+                    .with_ttl(config.key_ttl())
+                    .with_limits(limits),
+            ),
+        };
         keys_map.insert(pqkd.sae_id().to_string(), Arc::clone(&keys));
         let app_state_etsi = AppStateEtsi::build(
             pqkd.sae_id(),
             &config,
             keys,
             Arc::clone(&clients_map),
+            Arc::clone(&hops),
             Arc::clone(&hypercube),
+            health.clone(),
         )?;
         // clients_map.insert(
         //     pqkd.sae_id().to_string(),
@@ -112,7 +216,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
-    let app_state_relay = AppStateRelay::build(config.pqkds().clone(), clients_map, keys_map);
+    tokio::task::spawn(store::reap(
+        keys_map.clone(),
+        config
+            .key_ttl()
+            .clamp(Duration::from_secs(1), Duration::from_secs(60)),
+    ));
+
+    let app_state_relay = AppStateRelay::build(
+        config.pqkds().clone(),
+        clients_map,
+        hops,
+        keys_map,
+        Arc::clone(&hypercube),
+        health,
+    );
 
     let relay_server = RelayServer::build(app_state_relay, &config).await?;
 
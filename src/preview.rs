@@ -0,0 +1,286 @@
+use crate::config::{find_n_shortest_paths, Hypercube, PathMode, Pqkd};
+use crate::health::{LinkHealth, LinkState};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlannedPath {
+    /// Relay ids from the source relay to the target relay.
+    pub relays: Vec<String>,
+    /// SAE hop list the keys would be sent along.
+    pub saes: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Rejected {
+    pub relays: Vec<String>,
+    pub reason: String,
+}
+
+/// What `_enc_keys` would do for a key request from `source` to `target`,
+/// computed without requesting any key.
+#[derive(Serialize, Debug)]
+pub struct Preview {
+    source: String,
+    target: String,
+    /// The target is the remote SAE of the source's own QKD link, so keys
+    /// come straight from the KME and are not relayed.
+    direct: bool,
+    mode: PathMode,
+    threshold: Option<usize>,
+    paths: Vec<PlannedPath>,
+    spares: Vec<PlannedPath>,
+    rejected: Vec<Rejected>,
+    problems: Vec<String>,
+}
+
+impl Preview {
+    pub fn build(
+        hypercube: &Hypercube,
+        pqkds: &[Pqkd],
+        source: &str,
+        target: &str,
+        health: &LinkHealth,
+    ) -> Self {
+        let mut preview = Preview {
+            source: source.to_string(),
+            target: target.to_string(),
+            direct: pqkds
+                .iter()
+                .any(|p| p.sae_id() == source && p.remote_sae_id() == target),
+            mode: hypercube.path_mode(),
+            threshold: hypercube.threshold(),
+            paths: Vec::new(),
+            spares: Vec::new(),
+            rejected: Vec::new(),
+            problems: Vec::new(),
+        };
+        if preview.direct {
+            return preview;
+        }
+
+        let start = hypercube.find_relay(source);
+        let end = hypercube.find_relay(target);
+        if start.is_none() {
+            preview
+                .problems
+                .push(format!("SAE {} is not hosted by any relay", source));
+        }
+        if end.is_none() {
+            preview
+                .problems
+                .push(format!("SAE {} is not hosted by any relay", target));
+        }
+        let (Some(start), Some(end)) = (start, end) else {
+            return preview;
+        };
+        if start == end {
+            preview.problems.push(format!(
+                "SAE {} and SAE {} are both hosted by relay {}; keys cannot be relayed",
+                source, target, start
+            ));
+            return preview;
+        }
+
+        let chosen = hypercube.find_paths(start, end, health);
+        let spares = hypercube.find_spare_paths(start, end, health, &chosen);
+        for (relays, planned) in [
+            (&chosen, &mut preview.paths),
+            (&spares, &mut preview.spares),
+        ] {
+            for path in relays {
+                match hypercube.sae_path(path, source, target, health) {
+                    Some(saes) => planned.push(PlannedPath {
+                        relays: path.clone(),
+                        saes,
+                    }),
+                    None => preview.rejected.push(Rejected {
+                        relays: path.clone(),
+                        reason: "no QKD link translates the relay path into SAE hops".to_string(),
+                    }),
+                }
+            }
+        }
+
+        // Candidates on the topology as if every link were healthy, so the
+        // ones ruled out by link state are reported as well.
+        let all = hypercube.graph(&LinkHealth::default());
+        let n = hypercube.n();
+        let candidates = find_n_shortest_paths(&all, start, end, 2 * n + spares.len());
+        for path in candidates {
+            if chosen.contains(&path) || spares.contains(&path) {
+                continue;
+            }
+            let reason = unusable_link(hypercube, &path, health)
+                .or_else(|| shared_relay(hypercube.path_mode(), &path, &chosen))
+                .unwrap_or_else(|| format!("not among the {} cheapest paths", n));
+            preview.rejected.push(Rejected {
+                relays: path,
+                reason,
+            });
+        }
+
+        // XOR shares are split over however many paths exist; only Shamir
+        // sharing needs a minimum number of them.
+        let too_few = hypercube.threshold().filter(|k| preview.paths.len() < *k);
+        if preview.paths.is_empty() {
+            preview.problems.push(format!(
+                "no usable path from relay {} to relay {}",
+                start, end
+            ));
+        } else if let Some(k) = too_few {
+            preview.problems.push(format!(
+                "{} paths found but {} shares are required to rebuild the key",
+                preview.paths.len(),
+                k
+            ));
+        } else if preview.paths.len() < n {
+            preview
+                .problems
+                .push(format!("only {} of {} paths found", preview.paths.len(), n));
+        }
+        preview
+    }
+}
+
+/// Why the first hop of `path` with no usable QKD link is unusable.
+fn unusable_link(hypercube: &Hypercube, path: &[String], health: &LinkHealth) -> Option<String> {
+    path.windows(2).find_map(|hop| {
+        if hypercube.link(&hop[0], &hop[1], health).is_some() {
+            return None;
+        }
+        let (a, b) = hypercube.link(&hop[0], &hop[1], &LinkHealth::default())?;
+        let state = match health.get(a, b) {
+            Some(LinkState::Down) => "is down",
+            _ => "has no keys in store",
+        };
+        Some(format!("link {} - {} {}", a, b, state))
+    })
+}
+
+/// In disjoint mode, the intermediate relay `path` shares with a chosen path.
+fn shared_relay(mode: PathMode, path: &[String], chosen: &[Vec<String>]) -> Option<String> {
+    if mode != PathMode::Disjoint || path.len() < 3 {
+        return None;
+    }
+    let inner = &path[1..path.len() - 1];
+    chosen.iter().enumerate().find_map(|(i, c)| {
+        inner
+            .iter()
+            .find(|r| c.contains(r))
+            .map(|r| format!("shares relay {} with path {}", r, i + 1))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Preview;
+    use crate::config::Hypercube;
+    use crate::health::{LinkHealth, LinkState};
+
+    fn test_hypercube(extra: &str) -> Hypercube {
+        let toml = format!(
+            r#"
+dimension = 2
+{}
+
+[[relay]]
+id = "00"
+pqkds = ["Alice", "A0", "A1"]
+
+[[relay]]
+id = "01"
+pqkds = ["B0", "B1"]
+
+[[relay]]
+id = "10"
+pqkds = ["C0", "C1"]
+
+[[relay]]
+id = "11"
+pqkds = ["Bob", "D0", "D1"]
+
+[[connection]]
+first = "A0"
+second = "B0"
+
+[[connection]]
+first = "A1"
+second = "C0"
+cost = 2.0
+
+[[connection]]
+first = "B1"
+second = "D0"
+
+[[connection]]
+first = "C1"
+second = "D1"
+"#,
+            extra
+        );
+        toml::from_str(&toml).expect("valid hypercube")
+    }
+
+    #[test]
+    fn preview_lists_chosen_and_spare_paths() {
+        let hypercube = test_hypercube("n = 1\nretries = 1");
+        let preview = Preview::build(&hypercube, &[], "Alice", "Bob", &LinkHealth::default());
+
+        assert!(!preview.direct);
+        assert_eq!(preview.paths.len(), 1);
+        assert_eq!(preview.paths[0].relays, vec!["00", "01", "11"]);
+        assert_eq!(
+            preview.paths[0].saes,
+            vec!["Alice", "A0", "B0", "B1", "D0", "Bob"]
+        );
+        assert_eq!(preview.spares.len(), 1);
+        assert_eq!(preview.spares[0].relays, vec!["00", "10", "11"]);
+        assert!(preview.problems.is_empty());
+    }
+
+    #[test]
+    fn preview_explains_paths_ruled_out_by_link_state() {
+        let hypercube = test_hypercube("n = 1");
+        let health = LinkHealth::default();
+        health.update("A0", "B0", LinkState::Down);
+        let preview = Preview::build(&hypercube, &[], "Alice", "Bob", &health);
+
+        assert_eq!(preview.paths[0].relays, vec!["00", "10", "11"]);
+        assert_eq!(preview.rejected.len(), 1);
+        assert_eq!(preview.rejected[0].relays, vec!["00", "01", "11"]);
+        assert_eq!(preview.rejected[0].reason, "link A0 - B0 is down");
+    }
+
+    #[test]
+    fn preview_reports_missing_paths_for_threshold() {
+        let hypercube = test_hypercube("n = 3\nthreshold = 3\npaths = \"disjoint\"");
+        let preview = Preview::build(&hypercube, &[], "Alice", "Bob", &LinkHealth::default());
+
+        assert_eq!(preview.paths.len(), 2);
+        assert_eq!(
+            preview.problems,
+            vec!["2 paths found but 3 shares are required to rebuild the key"]
+        );
+    }
+
+    #[test]
+    fn preview_reports_fewer_xor_paths_as_a_warning() {
+        let hypercube = test_hypercube("n = 3\npaths = \"disjoint\"");
+        let preview = Preview::build(&hypercube, &[], "Alice", "Bob", &LinkHealth::default());
+
+        assert_eq!(preview.paths.len(), 2);
+        assert_eq!(preview.problems, vec!["only 2 of 3 paths found"]);
+    }
+
+    #[test]
+    fn preview_reports_unknown_sae() {
+        let hypercube = test_hypercube("n = 1");
+        let preview = Preview::build(&hypercube, &[], "Alice", "Mallory", &LinkHealth::default());
+
+        assert!(preview.paths.is_empty());
+        assert_eq!(
+            preview.problems,
+            vec!["SAE Mallory is not hosted by any relay"]
+        );
+    }
+}
@@ -2,5 +2,6 @@ mod error;
 mod server;
 mod state;
 
+pub use error::RelayServerError;
 pub use server::RelayServer;
-pub use state::AppStateRelay;
+pub use state::{AppStateRelay, Hops};
@@ -1,44 +1,136 @@
+use crate::etsi_server::ErrorBody;
+use axum::response::{IntoResponse, Response};
 use hyper::StatusCode;
+use serde_json::{json, Value};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum RelayServerError {
-    // #[error("io error")]
-    // IoError(#[from] std::io::Error),
-    // #[error("uri error")]
-    // UriError(#[from] axum::http::uri::InvalidUri),
-    // #[error("tls error")]
-    // TlsError(#[from] native_tls::Error),
-    // #[error("client error")]
-    // ClientError(#[from] hyper_util::client::legacy::Error),
-    // #[error("axum error")]
-    // AxumError(#[from] axum::Error),
-    // #[error("http error")]
-    // HttpError(#[from] axum::http::Error),
-    // #[error("serde_json error")]
-    // SerdeJsonError(#[from] serde_json::Error),
-    // #[error("Error")]
-    // EtsiServerBuildeError,
-    // #[error("Server dont't have data about pqkd with sae_id {0}")]
-    // UnknownPqkd(String),
-    // #[error("Error")]
-    // PathError,
-    // #[error("Error")]
-    // SendKeysError,
-    // #[error("Failed pqkd request: statuscode - {0}")]
-    // PqkdRequestError(StatusCode),
-    // #[error("Error")]
-    // GetKeysError,
+    #[error("client error")]
+    ClientError(#[from] hyper_util::client::legacy::Error),
+    #[error("axum error")]
+    AxumError(#[from] axum::Error),
+    #[error("http error")]
+    HttpError(#[from] axum::http::Error),
+    #[error("serde_json error")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Server dont't have data about pqkd with sae_id {0}")]
+    UnknownPqkd(String),
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error("Failed pqkd request: {0}")]
+    PqkdRequestError(String),
+    #[error("Failed relay request: statuscode - {0}")]
+    RelayRequestError(StatusCode, Option<ErrorBody>),
     #[error("Error")]
     AddKeyError,
     #[error("The keys received do not match.")]
     KeysDoNotMaych,
+    #[error("Share index or count does not match the stored key.")]
+    InvalidShare,
+    #[error("Key {0} was already delivered.")]
+    KeyDelivered(String),
+    #[error("No room left for keys from {0}.")]
+    StoreFull(String),
+    #[error("Hop not allowed: {0}")]
+    Unauthorized(String),
+    #[error("Keys from {0} fail the hop MAC")]
+    InvalidMac(String),
+    #[error("Path attestations do not verify: {0}")]
+    InvalidAttestation(crate::attestation::AttestationError),
+    #[error("Transfer {0} was already received")]
+    ReplayedTransfer(String),
+    #[error("Transfer {0} was sent outside the replay window")]
+    StaleTransfer(String),
+    #[error("Too many transfers in the replay window to take {0}")]
+    TooManyTransfers(String),
+    #[error("Attesting the hop failed: {0}")]
+    AttestationError(#[from] crate::attestation::AttestationError),
 }
+
+impl RelayServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RelayServerError::UnknownPqkd(_)
+            | RelayServerError::Unauthorized(_)
+            | RelayServerError::InvalidMac(_)
+            | RelayServerError::InvalidAttestation(_)
+            | RelayServerError::StaleTransfer(_) => StatusCode::UNAUTHORIZED,
+            RelayServerError::ReplayedTransfer(_) | RelayServerError::KeyDelivered(_) => {
+                StatusCode::CONFLICT
+            }
+            RelayServerError::InvalidPayload(_)
+            | RelayServerError::KeysDoNotMaych
+            | RelayServerError::InvalidShare => StatusCode::BAD_REQUEST,
+            RelayServerError::StoreFull(_)
+            | RelayServerError::TooManyTransfers(_)
+            | RelayServerError::RelayRequestError(StatusCode::SERVICE_UNAVAILABLE, _) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            RelayServerError::ClientError(_)
+            | RelayServerError::SerdeJsonError(_)
+            | RelayServerError::PqkdRequestError(_)
+            | RelayServerError::RelayRequestError(..) => StatusCode::BAD_GATEWAY,
+            RelayServerError::AxumError(_)
+            | RelayServerError::HttpError(_)
+            | RelayServerError::AddKeyError
+            | RelayServerError::AttestationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RelayServerError::ClientError(_) => "upstream_unreachable",
+            RelayServerError::AxumError(_) => "body_error",
+            RelayServerError::HttpError(_) => "http_error",
+            RelayServerError::SerdeJsonError(_) => "invalid_upstream_response",
+            RelayServerError::UnknownPqkd(_) => "unknown_sae",
+            RelayServerError::InvalidPayload(_) => "invalid_payload",
+            RelayServerError::PqkdRequestError(_) => "kme_request_failed",
+            RelayServerError::RelayRequestError(..) => "relay_request_failed",
+            RelayServerError::AddKeyError => "key_store_error",
+            RelayServerError::KeysDoNotMaych => "keys_do_not_match",
+            RelayServerError::InvalidShare => "invalid_share",
+            RelayServerError::KeyDelivered(_) => "key_delivered",
+            RelayServerError::StoreFull(_) => "store_full",
+            RelayServerError::Unauthorized(_) => "unauthorized",
+            RelayServerError::InvalidMac(_) => "invalid_mac",
+            RelayServerError::InvalidAttestation(_) => "invalid_attestation",
+            RelayServerError::ReplayedTransfer(_) => "replayed_transfer",
+            RelayServerError::StaleTransfer(_) => "stale_transfer",
+            RelayServerError::TooManyTransfers(_) => "too_many_transfers",
+            RelayServerError::AttestationError(_) => "attestation_error",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let (extra, more) = match self {
+            RelayServerError::UnknownPqkd(sae_id)
+            | RelayServerError::StoreFull(sae_id)
+            | RelayServerError::InvalidMac(sae_id) => (json!({ "sae_id": sae_id }), Vec::new()),
+            RelayServerError::ReplayedTransfer(transfer_id)
+            | RelayServerError::StaleTransfer(transfer_id)
+            | RelayServerError::TooManyTransfers(transfer_id) => {
+                (json!({ "transfer_id": transfer_id }), Vec::new())
+            }
+            RelayServerError::RelayRequestError(code, body) => (
+                json!({ "status": code.as_u16() }),
+                body.as_ref().map(|b| b.details.clone()).unwrap_or_default(),
+            ),
+            _ => (Value::Null, Vec::new()),
+        };
+        ErrorBody::new(self.to_string(), self.reason(), extra, more)
+    }
+}
+
+impl IntoResponse for RelayServerError {
+    fn into_response(self) -> Response {
+        self.body().into_response(self.status())
+    }
+}
+
 impl From<RelayServerError> for StatusCode {
-    fn from(_val: RelayServerError) -> Self {
-        //match val {
-        //    _ => StatusCode::INTERNAL_SERVER_ERROR,
-        //}
-        StatusCode::INTERNAL_SERVER_ERROR
+    fn from(val: RelayServerError) -> Self {
+        val.status()
     }
 }
@@ -1,16 +1,23 @@
+use super::error::RelayServerError;
 use super::state::AppStateRelay;
 use crate::config::Config;
-use crate::etsi_server::{DataKeys, Key, Keys, Prom};
+use crate::config::ServerTls;
+use crate::etsi_server::{Client, DataKeys, Key, Keys, Prom};
+use crate::preview::Preview;
+use crate::tls::{self, Peer};
+use crate::topology::{Format, Topology};
 use crate::util;
 use axum::{
     body::Body,
-    extract::{Json, State},
+    extract::{Extension, Json, Query, State},
+    http::header,
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Router,
 };
-use hyper::StatusCode;
+use serde::Deserialize;
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
 use base64::prelude::*;
 use std::time::Duration;
@@ -26,6 +33,7 @@ use axum::{
 pub struct RelayServer {
     app: Router,
     listener: TcpListener,
+    tls: Option<(TlsAcceptor, ServerTls)>,
 }
 
 impl RelayServer {
@@ -36,6 +44,8 @@ impl RelayServer {
         let app = Router::new()
             //.route("/keys", post(request_keys))
             .route("/info_keys", post(info_keys))
+            .route("/topology", get(topology))
+            .route("/paths", get(paths))
             .with_state(state)
             .layer(
                 TraceLayer::new_for_http()
@@ -79,36 +89,132 @@ impl RelayServer {
                     ),
             );
 
+        let tls = match config.tls() {
+            Some(tls) => Some((
+                tls::acceptor(tls).map_err(std::io::Error::other)?,
+                tls.clone(),
+            )),
+            None => {
+                tracing::warn!(
+                    "Relay {} serves /info_keys over plain HTTP: hops cannot be authenticated",
+                    config.id()
+                );
+                None
+            }
+        };
         let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port())).await?;
 
-        Ok(RelayServer { app, listener })
+        Ok(RelayServer { app, listener, tls })
     }
 
     pub async fn run(self) -> Result<(), std::io::Error> {
-        axum::serve(self.listener, self.app).await
+        match self.tls {
+            Some((acceptor, tls)) => tls::serve(self.listener, acceptor, tls, self.app).await,
+            None => axum::serve(self.listener, self.app).await,
+        }
     }
 }
 
+#[derive(Deserialize)]
+struct TopologyQuery {
+    #[serde(default)]
+    format: Format,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+async fn topology(
+    State(state): State<AppStateRelay>,
+    Query(query): Query<TopologyQuery>,
+) -> Response {
+    let route = query.from.as_deref().zip(query.to.as_deref());
+    let topology = Topology::build(state.hypercube(), route, state.health());
+    let content_type = match query.format {
+        Format::Dot => "text/vnd.graphviz",
+        Format::Mermaid => "text/plain",
+        Format::Json => "application/json",
+    };
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        topology.render(query.format),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct PathsQuery {
+    from: String,
+    to: String,
+}
+
+async fn paths(State(state): State<AppStateRelay>, Query(query): Query<PathsQuery>) -> Response {
+    let preview = Preview::build(
+        state.hypercube(),
+        state.pqkds(),
+        &query.from,
+        &query.to,
+        state.health(),
+    );
+    Json(preview).into_response()
+}
+
 async fn info_keys(
     State(state): State<AppStateRelay>,
+    peer: Option<Extension<Peer>>,
     Json(payload): Json<DataKeys>,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, RelayServerError> {
     tracing::info!(
         "Received keys from {} for {}",
         payload.from(),
-        payload.path().last().ok_or(StatusCode::BAD_REQUEST)?
+        payload.path().last().ok_or_else(empty_path)?
     );
-    let keys = if let Ok(keys) = get_keys(payload.to(), &state, payload.keys()).await {
-        keys
-    } else {
-        return Ok(Response::new(Body::empty()).into_response());
+    check_hop(&state, peer.as_ref().map(|p| p.0 .0.as_str()), &payload)?;
+    // A replayed message is refused before it costs any key of the KME. Its
+    // id is claimed at once, so of two copies arriving together only one
+    // gets further, and released again unless the MAC verifies, so forged
+    // messages can neither fill the window nor claim the id of a genuine one.
+    let replay = state.hops().replay();
+    replay.claim(
+        payload.transfer_id(),
+        payload.timestamp(),
+        util::unix_millis(),
+    )?;
+
+    // Keys are stored on this relay: every hop that brought them here must
+    // be attested. The chain is checked before it costs any key of the KME.
+    let destination = payload.path().last().ok_or_else(empty_path)?;
+    if let (Some(attestor), Some(_)) = (
+        state.hops().attestor(),
+        state.pqkd(|p| p.sae_id() == destination),
+    ) {
+        if let Err(e) = attestor.verify(
+            &payload,
+            state.hypercube(),
+            replay.window(),
+            util::unix_millis(),
+        ) {
+            replay.release(payload.transfer_id(), payload.timestamp());
+            return Err(RelayServerError::InvalidAttestation(e));
+        }
+    }
+    let keys = match get_keys(&state, &payload).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            if matches!(
+                e,
+                RelayServerError::InvalidMac(_) | RelayServerError::InvalidPayload(_)
+            ) {
+                replay.release(payload.transfer_id(), payload.timestamp());
+            }
+            return Err(e);
+        }
     };
 
     let pqkd = state
         .pqkd(|p| p.sae_id() == payload.to())
-        .ok_or(StatusCode::BAD_REQUEST)?;
+        .ok_or_else(|| RelayServerError::UnknownPqkd(payload.to().to_string()))?;
 
-    let last = payload.path().last().ok_or(StatusCode::BAD_REQUEST)?;
+    let last = payload.path().last().ok_or_else(empty_path)?;
     if last == pqkd.sae_id() {
         for key in keys {
             tracing::info!(
@@ -121,227 +227,429 @@ async fn info_keys(
                 .add_key(
                     pqkd.sae_id(),
                     payload.path()[0].to_string(),
-                    key.key_id,
-                    key.key,
+                    payload.share(),
+                    key,
                 )
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                .await?;
         }
         Ok(Response::new(Body::empty()).into_response())
     } else {
-        match send_keys(&state, pqkd.sae_id(), payload.path(), keys).await {
-            Ok(_) => Ok(Response::new(Body::empty())),
-            // todo ERROR
-            Err(_) => Ok(Response::new(Body::empty()).into_response()),
-        }
+        // A full store further down the path answers 503, which is passed
+        // back so the origin can back off.
+        send_keys(&state, pqkd.sae_id(), &payload, keys).await?;
+        Ok(Response::new(Body::empty()))
     }
 }
 
 async fn send_keys(
     state: &AppStateRelay,
     sae_id: &str,
-    path: &[String],
+    payload: &DataKeys,
     keys: Vec<Key>,
-) -> Result<(), StatusCode> {
-    let position = path
-        .iter()
-        .position(|i| i == sae_id)
-        .ok_or(StatusCode::BAD_REQUEST)?;
-    let next_pqkd = path.get(position + 1).ok_or(StatusCode::BAD_REQUEST)?;
+) -> Result<(), RelayServerError> {
+    let (path, share) = (payload.path().as_slice(), payload.share());
+    let position = path.iter().position(|i| i == sae_id).ok_or_else(|| {
+        RelayServerError::InvalidPayload(format!("{} is not on the path", sae_id))
+    })?;
+    let next_pqkd = path
+        .get(position + 1)
+        .ok_or_else(|| RelayServerError::InvalidPayload(format!("path ends at {}", sae_id)))?;
 
     let pqkd = state
         .pqkd(|p| p.sae_id() == next_pqkd)
-        .ok_or(StatusCode::BAD_REQUEST)?;
+        .ok_or_else(|| RelayServerError::UnknownPqkd(next_pqkd.to_string()))?;
 
     if position + 1 == path.len() - 1 {
         for key in keys {
             tracing::info!("Save key from {:?} with key_ID: {:?}", path[0], key.key_id);
 
             state
-                .add_key(pqkd.sae_id(), path[0].to_string(), key.key_id, key.key)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                .add_key(pqkd.sae_id(), path[0].to_string(), share, key)
+                .await?;
         }
         return Ok(());
     }
 
     tracing::info!("Send keys to next node {}", pqkd.remote_sae_id());
 
-    let client = state.client(pqkd.sae_id()).ok_or(StatusCode::BAD_REQUEST)?;
+    let client = state
+        .client(pqkd.sae_id())
+        .ok_or_else(|| RelayServerError::UnknownPqkd(pqkd.sae_id().to_string()))?;
+
+    // Keys always arrive from a previous relay, never from the origin: each
+    // one is masked afresh with a key of the next link.
+    let number = keys.len();
+    let first_key = keys
+        .first()
+        .ok_or_else(|| RelayServerError::InvalidPayload("no keys".to_string()))?;
+    let size = BASE64_STANDARD
+        .decode(first_key.key.clone())
+        .map_err(|e| RelayServerError::InvalidPayload(e.to_string()))?
+        .len()
+        * 8;
+
+    let req = hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/api/v1/keys/{}/enc_keys?size={}&number={}",
+            pqkd.kme_address(),
+            pqkd.remote_sae_id(),
+            size,
+            number
+        ))
+        .body(Body::empty())?;
+
+    let keys_for_xor = kme_keys(client, req).await?;
+    if keys_for_xor.len() != keys.len() {
+        return Err(RelayServerError::PqkdRequestError(format!(
+            "{} keys asked, {} received",
+            keys.len(),
+            keys_for_xor.len()
+        )));
+    }
 
-    let data = if position == 0 {
-        let keys_ids: Vec<String> = keys.iter().map(|k| k.key_id.clone()).collect();
-        let keys_for_send: Vec<Prom> = keys_ids
-            .iter()
-            .map(|k| Prom::new(String::from(k), None, None))
-            .collect();
-        DataKeys::new(
-            String::from(pqkd.sae_id()),
-            String::from(pqkd.remote_sae_id()),
-            Vec::from(path),
-            keys_for_send,
-        )
-    } else {
-        let number = keys.len();
-        let first_key = keys.first().ok_or(StatusCode::BAD_REQUEST)?;
-        let size = BASE64_STANDARD
-            .decode(first_key.key.clone())
-            .map_err(|_| StatusCode::BAD_REQUEST)?
-            .len()
-            * 8;
-
-        let req = hyper::Request::builder()
-            .method(hyper::Method::GET)
-            .uri(format!(
-                "{}/api/v1/keys/{}/enc_keys?size={}&number={}",
-                pqkd.kme_address(),
-                pqkd.remote_sae_id(),
-                size,
-                number
-            ))
-            .body(Body::empty())
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let res = client
-            .request(req)
-            .await
-            .map_err(|_| StatusCode::BAD_GATEWAY)?
-            .into_response();
-
-        let body_bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
-            .await
-            .map_err(|_| StatusCode::BAD_GATEWAY)?;
-
-        let keys_for_xor: Keys =
-            serde_json::from_slice(&body_bytes[..]).map_err(|_| StatusCode::BAD_GATEWAY)?;
-        let keys_for_xor = keys_for_xor.keys();
-        if keys_for_xor.len() != keys.len() {
-            return Err(StatusCode::BAD_GATEWAY);
-        }
+    let mut keys_for_send = Vec::new();
+
+    for i in 0..keys.len() {
+        keys_for_send.push(Prom::new(
+            keys[i].key_id.clone(),
+            Some(keys_for_xor[i].key_id.clone()),
+            Some(util::xor(
+                keys[i].key.as_bytes().to_vec(),
+                keys_for_xor[i].key.as_bytes().to_vec(),
+            )),
+            keys[i].extensions.clone(),
+        ));
+    }
 
-        let mut keys_for_send = Vec::new();
-
-        for i in 0..keys.len() {
-            keys_for_send.push(Prom::new(
-                keys[i].key_id.clone(),
-                Some(keys_for_xor[i].key_id.clone()),
-                Some(util::xor(
-                    keys[i].key.as_bytes().to_vec(),
-                    keys_for_xor[i].key.as_bytes().to_vec(),
-                )),
-            ));
-        }
+    let mut data = DataKeys::new(
+        String::from(pqkd.sae_id()),
+        String::from(pqkd.remote_sae_id()),
+        Vec::from(path),
+        share,
+        keys_for_send,
+    )
+    .with_attestations(payload.attestations().to_vec());
+    if let Some(attestor) = state.hops().attestor() {
+        attestor.attest(&mut data)?;
+    }
+    // One more link key authenticates the message to the next relay.
+    let request = hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/api/v1/keys/{}/enc_keys?size={}&number=1",
+            pqkd.kme_address(),
+            pqkd.remote_sae_id(),
+            util::MAC_KEY_LEN * 8
+        ))
+        .body(Body::empty())?;
+    let mac_key = kme_keys(client, request)
+        .await?
+        .pop()
+        .ok_or_else(|| RelayServerError::PqkdRequestError("no MAC key".to_string()))?;
+    let mac_bytes = BASE64_STANDARD
+        .decode(&mac_key.key)
+        .map_err(|e| RelayServerError::PqkdRequestError(e.to_string()))?;
+    if !data.seal(mac_key.key_id, &mac_bytes)? {
+        return Err(RelayServerError::PqkdRequestError(format!(
+            "MAC key of {} bytes",
+            mac_bytes.len()
+        )));
+    }
 
-        DataKeys::new(
-            String::from(pqkd.sae_id()),
-            String::from(pqkd.remote_sae_id()),
-            Vec::from(path),
-            keys_for_send,
-        )
-    };
     let request = hyper::Request::builder()
         .method(hyper::Method::POST)
         .uri(format!("{}/info_keys", pqkd.remote_proxy_address()))
         .header("content-type", "application/json")
-        .body(Body::new(
-            serde_json::to_string(&data).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-        ))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let _ = client
-        .request(request)
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?
-        .into_response();
+        .body(Body::new(serde_json::to_string(&data)?))?;
+
+    let res = state.hops().client().request(request).await?;
+    if !res.status().is_success() {
+        let status = res.status();
+        let body = axum::body::to_bytes(Body::new(res.into_body()), usize::MAX).await?;
+        return Err(RelayServerError::RelayRequestError(
+            status,
+            serde_json::from_slice(&body).ok(),
+        ));
+    }
 
     Ok(())
 }
 
-async fn get_keys(
-    sae_id: &str,
-    state: &AppStateRelay,
-    payload_keys: &Vec<Prom>,
-) -> Result<Vec<Key>, StatusCode> {
+async fn get_keys(state: &AppStateRelay, payload: &DataKeys) -> Result<Vec<Key>, RelayServerError> {
+    let sae_id = payload.to();
     let mut keys: Vec<Key> = Vec::new();
 
     let pqkd = state
         .pqkd(|p| p.sae_id() == sae_id)
-        .ok_or(StatusCode::BAD_REQUEST)?;
-    let client = state.client(sae_id).ok_or(StatusCode::BAD_REQUEST)?;
-
-    for key in payload_keys {
-        match (key.key_id(), key.key_id_xor(), key.key()) {
-            // jesli proxy przekazuje kluczy proxy obok
-            (k_id, None, Some(k)) => {
-                keys.push(Key {
-                    key_id: String::from(k_id),
-                    key: String::from_utf8(k.clone()).map_err(|_| StatusCode::BAD_REQUEST)?,
-                });
-            }
-            // jesli wysyla pierwszy wezel
-            (k_id, None, None) => {
-                let request = hyper::Request::builder()
-                    .method(hyper::Method::GET)
-                    .uri(format!(
-                        "{}/api/v1/keys/{}/dec_keys?key_ID={}",
-                        pqkd.kme_address(),
-                        pqkd.remote_sae_id(),
-                        k_id,
-                    ))
-                    .body(Body::empty())
-                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-                let response = client
-                    .request(request)
-                    .await
-                    .map_err(|_| StatusCode::BAD_GATEWAY)?
-                    .into_response();
-                let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
-                    .await
-                    .map_err(|_| StatusCode::BAD_GATEWAY)?;
-                let keys_from_pqkd: Keys =
-                    serde_json::from_slice(&body_bytes[..]).map_err(|_| StatusCode::BAD_GATEWAY)?;
-                let keys_from_pqkd = keys_from_pqkd.keys();
-                let key_from_pqkd = keys_from_pqkd.first().ok_or(StatusCode::BAD_GATEWAY)?;
-                keys.push(Key {
-                    key: key_from_pqkd.key.to_string(),
-                    key_id: key_from_pqkd.key_id.to_string(),
-                });
-            }
-            // jesli wezel posredni wysyla kluczy nastepnemu pqkd
-            (k_id, Some(k_id_xor), Some(k)) => {
-                let request = hyper::Request::builder()
-                    .method(hyper::Method::GET)
-                    .uri(format!(
-                        "{}/api/v1/keys/{}/dec_keys?key_ID={}",
-                        pqkd.kme_address(),
-                        pqkd.remote_sae_id(),
-                        k_id_xor,
-                    ))
-                    .body(Body::empty())
-                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-                let response = client
-                    .request(request)
-                    .await
-                    .map_err(|_| StatusCode::BAD_GATEWAY)?
-                    .into_response();
-                let body_bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
-                    .await
-                    .map_err(|_| StatusCode::BAD_GATEWAY)?;
-                let keys_from_pqkd: Keys =
-                    serde_json::from_slice(&body_bytes[..]).map_err(|_| StatusCode::BAD_GATEWAY)?;
-                let keys_from_pqkd = keys_from_pqkd.keys();
-                let key_from_pqkd = keys_from_pqkd.first().ok_or(StatusCode::BAD_GATEWAY)?;
-                let key_before_xor = util::xor(k.clone(), key_from_pqkd.key.as_bytes().to_vec());
-                let key_to_string =
-                    String::from_utf8(key_before_xor).map_err(|_| StatusCode::BAD_REQUEST)?;
-                let k = Key {
-                    key: key_to_string,
-                    key_id: String::from(k_id),
-                };
-                keys.push(k);
-            }
-            _ => {}
-        };
+        .ok_or_else(|| RelayServerError::UnknownPqkd(sae_id.to_string()))?;
+    let client = state
+        .client(sae_id)
+        .ok_or_else(|| RelayServerError::UnknownPqkd(sae_id.to_string()))?;
+
+    // Every share travels masked with a key of the link; anything else is
+    // refused before a single key of the KME is spent on it.
+    let masked = payload
+        .keys()
+        .iter()
+        .map(|key| match (key.key_id_xor(), key.key()) {
+            (Some(k_id_xor), Some(k)) => Ok((k_id_xor, k)),
+            _ => Err(RelayServerError::InvalidPayload(format!(
+                "key {} is not masked with a key of the link",
+                key.key_id()
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Nothing is unmasked before the sender proves it holds the MAC key of
+    // the link; tampered or untagged messages stop here.
+    let mac = payload
+        .mac()
+        .ok_or_else(|| RelayServerError::InvalidMac(payload.from().to_string()))?;
+    let request = hyper::Request::builder()
+        .method(hyper::Method::GET)
+        .uri(format!(
+            "{}/api/v1/keys/{}/dec_keys?key_ID={}",
+            pqkd.kme_address(),
+            pqkd.remote_sae_id(),
+            mac.key_id,
+        ))
+        .body(Body::empty())?;
+    let mac_key = kme_keys(client, request).await?.pop().ok_or_else(|| {
+        RelayServerError::PqkdRequestError(format!("no key with key_ID {}", mac.key_id))
+    })?;
+    let verified = BASE64_STANDARD
+        .decode(&mac_key.key)
+        .is_ok_and(|key| payload.verify(&key));
+    if !verified {
+        tracing::warn!("Hop MAC from {} to {} failed", payload.from(), sae_id);
+        return Err(RelayServerError::InvalidMac(payload.from().to_string()));
+    }
+
+    for (key, (k_id_xor, k)) in payload.keys().iter().zip(masked) {
+        let request = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(format!(
+                "{}/api/v1/keys/{}/dec_keys?key_ID={}",
+                pqkd.kme_address(),
+                pqkd.remote_sae_id(),
+                k_id_xor,
+            ))
+            .body(Body::empty())?;
+
+        let keys_from_pqkd = kme_keys(client, request).await?;
+        let key_from_pqkd = keys_from_pqkd.first().ok_or_else(|| {
+            RelayServerError::PqkdRequestError(format!("no key with key_ID {}", key.key_id()))
+        })?;
+        let key_before_xor = util::xor(k.clone(), key_from_pqkd.key.as_bytes().to_vec());
+        let key_to_string =
+            String::from_utf8(key_before_xor).map_err(|_| RelayServerError::InvalidShare)?;
+        keys.push(Key {
+            key: key_to_string.into(),
+            key_id: key.key_id().to_string(),
+            extensions: key.extensions().clone(),
+        });
     }
     Ok(keys)
 }
+
+/// Keys from a KME `enc_keys` or `dec_keys` request.
+async fn kme_keys(
+    client: &Client,
+    request: hyper::Request<Body>,
+) -> Result<Vec<Key>, RelayServerError> {
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(RelayServerError::PqkdRequestError(format!(
+            "statuscode - {}",
+            response.status()
+        )));
+    }
+    let body_bytes = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX).await?;
+    let keys: Keys = serde_json::from_slice(&body_bytes[..])?;
+    Ok(keys.keys())
+}
+
+/// Rejects keys that did not come over a link of this relay: `to` must be a
+/// local SAE whose link partner is `from`, the two must be neighbours on the
+/// path, and over TLS `from` must be hosted on the relay that `peer`
+/// authenticated as.
+fn check_hop(
+    state: &AppStateRelay,
+    peer: Option<&str>,
+    payload: &DataKeys,
+) -> Result<(), RelayServerError> {
+    let (from, to) = (payload.from(), payload.to());
+    if state
+        .pqkd(|p| p.sae_id() == to && p.remote_sae_id() == from)
+        .is_none()
+    {
+        return Err(RelayServerError::Unauthorized(format!(
+            "no link from {} to {}",
+            from, to
+        )));
+    }
+    if !payload
+        .path()
+        .windows(2)
+        .any(|hop| hop[0] == from && hop[1] == to)
+    {
+        return Err(RelayServerError::Unauthorized(format!(
+            "{} to {} is not a hop of the path",
+            from, to
+        )));
+    }
+    if let Some(peer) = peer {
+        let relay = state.hypercube().find_relay(from);
+        if relay != Some(peer) {
+            tracing::warn!("Relay {} sent keys as {} of {:?}", peer, from, relay);
+            return Err(RelayServerError::Unauthorized(format!(
+                "{} is not hosted on relay {}",
+                from, peer
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn empty_path() -> RelayServerError {
+    RelayServerError::InvalidPayload("empty path".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_hop, get_keys};
+    use crate::config::Config;
+    use crate::etsi_server::{Client, DataKeys, Prom, Share};
+    use crate::health::LinkHealth;
+    use crate::relay_server::{AppStateRelay, Hops, RelayServerError};
+    use crate::tls;
+    use hyper_tls::HttpsConnector;
+    use hyper_util::rt::TokioExecutor;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn state() -> AppStateRelay {
+        let config: Config = toml::from_str(
+            r#"
+id = "01"
+port = 4001
+
+[[pqkds]]
+port = 3001
+sae_id = "Bob"
+remote_sae_id = "Alice"
+remote_proxy_address = "https://127.0.0.1:4000"
+kme_address = "http://127.0.0.1:8081"
+"#,
+        )
+        .expect("valid config");
+        let hypercube = toml::from_str(
+            r#"
+dimension = 1
+n = 1
+
+[[relay]]
+id = "00"
+pqkds = ["Alice"]
+
+[[relay]]
+id = "01"
+pqkds = ["Bob"]
+
+[[connection]]
+first = "Alice"
+second = "Bob"
+"#,
+        )
+        .expect("valid hypercube");
+        let kme: Client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+            .build(HttpsConnector::new());
+        AppStateRelay::build(
+            config.pqkds().clone(),
+            Arc::new(HashMap::from([("Bob".to_string(), Arc::new(kme))])),
+            Arc::new(Hops::new(
+                tls::relay_client(None).expect("relay client"),
+                None,
+            )),
+            HashMap::new(),
+            Arc::new(hypercube),
+            LinkHealth::default(),
+        )
+    }
+
+    fn data_keys(from: &str, to: &str, path: &[&str]) -> DataKeys {
+        DataKeys::new(
+            from.to_string(),
+            to.to_string(),
+            path.iter().map(|s| s.to_string()).collect(),
+            Share {
+                index: 0,
+                total: 1,
+                threshold: None,
+            },
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn hops_must_match_the_link_path_and_peer() {
+        let state = state();
+        let hop = data_keys("Alice", "Bob", &["Alice", "Bob"]);
+        assert!(check_hop(&state, None, &hop).is_ok());
+        assert!(check_hop(&state, Some("00"), &hop).is_ok());
+
+        // Relay 02 holds a valid certificate but does not host Alice.
+        let spoofed = check_hop(&state, Some("02"), &hop);
+        assert!(matches!(spoofed, Err(RelayServerError::Unauthorized(_))));
+
+        let no_link = data_keys("Carol", "Bob", &["Carol", "Bob"]);
+        assert!(matches!(
+            check_hop(&state, None, &no_link),
+            Err(RelayServerError::Unauthorized(_))
+        ));
+
+        let off_path = data_keys("Alice", "Bob", &["Alice", "Carol", "Bob"]);
+        assert!(matches!(
+            check_hop(&state, Some("00"), &off_path),
+            Err(RelayServerError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn untagged_keys_are_rejected_before_unmasking() {
+        let state = state();
+        let hop = data_keys("Alice", "Bob", &["Alice", "Bob"]);
+        let rejected = get_keys(&state, &hop).await;
+        assert!(matches!(rejected, Err(RelayServerError::InvalidMac(from)) if from == "Alice"));
+    }
+
+    #[tokio::test]
+    async fn unmasked_keys_are_rejected() {
+        let state = state();
+        for (key_id_xor, key) in [
+            (None, Some(b"plain".to_vec())),
+            (None, None),
+            (Some("x1".to_string()), None),
+        ] {
+            let hop = DataKeys::new(
+                "Alice".to_string(),
+                "Bob".to_string(),
+                vec!["Alice".to_string(), "Bob".to_string()],
+                Share {
+                    index: 0,
+                    total: 1,
+                    threshold: None,
+                },
+                vec![Prom::new(
+                    "k1".to_string(),
+                    key_id_xor,
+                    key,
+                    Default::default(),
+                )],
+            );
+            let rejected = get_keys(&state, &hop).await;
+            assert!(matches!(rejected, Err(RelayServerError::InvalidPayload(_))));
+        }
+    }
+}
@@ -1,27 +1,160 @@
-use crate::config::Pqkd;
-use crate::etsi_server::{Client, KeyReceived};
-use std::collections::HashMap;
+use crate::attestation::Attestor;
+use crate::config::{Hypercube, Pqkd};
+use crate::etsi_server::{Client, Key, Share};
+use crate::health::LinkHealth;
+use crate::store::{KeyStore, StoreError};
+use crate::tls::RelayClient;
+use dashmap::DashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use super::error::RelayServerError;
 
+/// What relays need to pass keys on to each other: the client for
+/// `/info_keys` of neighbours, the replay window of received transfers and,
+/// when configured, the attestor signing and checking hops.
+pub struct Hops {
+    client: RelayClient,
+    attestor: Option<Attestor>,
+    replay: ReplayWindow,
+}
+
+impl Hops {
+    pub fn new(client: RelayClient, attestor: Option<Attestor>) -> Hops {
+        Hops {
+            client,
+            attestor,
+            replay: ReplayWindow::new(Duration::from_secs(300)),
+        }
+    }
+
+    pub fn with_replay_window(mut self, window: Duration) -> Hops {
+        self.replay = ReplayWindow::new(window);
+        self
+    }
+
+    pub fn replay(&self) -> &ReplayWindow {
+        &self.replay
+    }
+
+    pub fn client(&self) -> &RelayClient {
+        &self.client
+    }
+
+    pub fn attestor(&self) -> Option<&Attestor> {
+        self.attestor.as_ref()
+    }
+}
+
+/// Most transfer ids a [`ReplayWindow`] holds at once.
+const MAX_TRANSFERS: usize = 100_000;
+
+/// Transfer ids received on `/info_keys` within the last `window`. A message
+/// whose timestamp is further than `window` from the local clock is refused,
+/// so an id can be forgotten once its timestamp leaves the window. Ids are
+/// queued in the order they were claimed and dropped from the front once
+/// expired.
+pub struct ReplayWindow {
+    window: u64,
+    max: usize,
+    seen: DashMap<String, u64>,
+    expiry: Mutex<VecDeque<(u64, String)>>,
+}
+
+impl ReplayWindow {
+    pub fn new(window: Duration) -> ReplayWindow {
+        ReplayWindow {
+            window: window.as_millis() as u64,
+            max: MAX_TRANSFERS,
+            seen: DashMap::new(),
+            expiry: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Width of the window in milliseconds.
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /// Claims transfer `id` sent at `timestamp`, both in milliseconds since
+    /// the Unix epoch. Fails if `timestamp` is outside the window around
+    /// `now`, if `id` is already claimed, or if the window already holds as
+    /// many ids as it may after dropping the expired ones.
+    pub fn claim(&self, id: &str, timestamp: u64, now: u64) -> Result<(), RelayServerError> {
+        if id.is_empty() {
+            return Err(RelayServerError::InvalidPayload(
+                "missing transfer_id".to_string(),
+            ));
+        }
+        if timestamp.abs_diff(now) > self.window {
+            return Err(RelayServerError::StaleTransfer(id.to_string()));
+        }
+        let mut expiry = self.expiry.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some((expires, _)) = expiry.front() {
+            if *expires >= now {
+                break;
+            }
+            if let Some((_, expired)) = expiry.pop_front() {
+                self.seen.remove(&expired);
+            }
+        }
+        if self.seen.len() >= self.max && !self.seen.contains_key(id) {
+            return Err(RelayServerError::TooManyTransfers(id.to_string()));
+        }
+        match self.seen.entry(id.to_string()) {
+            dashmap::Entry::Occupied(_) => Err(RelayServerError::ReplayedTransfer(id.to_string())),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(timestamp);
+                expiry.push_back((timestamp.saturating_add(self.window), id.to_string()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Gives up the claim on transfer `id` sent at `timestamp`, for a
+    /// message that turned out not to be authentic. The claim was queued
+    /// recently, so it is looked for from the back.
+    pub fn release(&self, id: &str, timestamp: u64) {
+        let mut expiry = self.expiry.lock().unwrap_or_else(|e| e.into_inner());
+        if self
+            .seen
+            .remove_if(id, |_, seen| *seen == timestamp)
+            .is_some()
+        {
+            if let Some(position) = expiry.iter().rposition(|(_, claimed)| claimed == id) {
+                expiry.remove(position);
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AppStateRelay {
     pqkds: Vec<Pqkd>,
     clients: Arc<HashMap<String, Arc<Client>>>,
-    keys: HashMap<String, Arc<Mutex<Vec<KeyReceived>>>>,
+    hops: Arc<Hops>,
+    keys: HashMap<String, Arc<dyn KeyStore>>,
+    hypercube: Arc<Hypercube>,
+    health: LinkHealth,
 }
 
 impl AppStateRelay {
     pub fn build(
         pqkds: Vec<Pqkd>,
         clients: Arc<HashMap<String, Arc<Client>>>,
-        keys: HashMap<String, Arc<Mutex<Vec<KeyReceived>>>>,
+        hops: Arc<Hops>,
+        keys: HashMap<String, Arc<dyn KeyStore>>,
+        hypercube: Arc<Hypercube>,
+        health: LinkHealth,
     ) -> AppStateRelay {
         AppStateRelay {
             pqkds,
             clients,
+            hops,
             keys,
+            hypercube,
+            health,
         }
     }
 
@@ -36,49 +169,65 @@ impl AppStateRelay {
         self.pqkds.iter().find(predicate)
     }
 
+    pub fn pqkds(&self) -> &[Pqkd] {
+        &self.pqkds
+    }
+
     pub fn client(&self, sae_id: &str) -> Option<&Arc<Client>> {
         self.clients.get(sae_id)
     }
 
-    pub fn add_key(
+    pub fn hops(&self) -> &Hops {
+        &self.hops
+    }
+
+    pub fn hypercube(&self) -> &Arc<Hypercube> {
+        &self.hypercube
+    }
+
+    pub fn health(&self) -> &LinkHealth {
+        &self.health
+    }
+
+    /// Stores one share on a blocking thread, as the durable store commits
+    /// it to disk before returning.
+    pub async fn add_key(
         &self,
         sae_id: &str,
         from: String,
-        key_id: String,
-        key: String,
+        share: Share,
+        key: Key,
     ) -> Result<(), RelayServerError> {
-        let mut keys = self
-            .keys
-            .get(sae_id)
-            .ok_or(RelayServerError::AddKeyError)?
-            .lock()
-            .map_err(|_| RelayServerError::AddKeyError)?;
-
-        let is_save = keys
-            .iter()
-            .position(|k| k.from == from && k.key_id == key_id);
-
-        if let Some(p) = is_save {
-            if keys[p].key == key {
-                keys[p].num();
-                Ok(())
-            } else {
-                Err(RelayServerError::KeysDoNotMaych)
+        let keys = Arc::clone(self.keys.get(sae_id).ok_or(RelayServerError::AddKeyError)?);
+        let added = tokio::task::spawn_blocking(move || keys.add_share(from, share, key))
+            .await
+            .map_err(|e| {
+                tracing::error!("Key store task failed: {}", e);
+                RelayServerError::AddKeyError
+            })?;
+        added.map_err(|e| match e {
+            StoreError::Share(e) => e,
+            StoreError::Full(from) => RelayServerError::StoreFull(from),
+            e => {
+                tracing::error!("Key store error: {}", e);
+                RelayServerError::AddKeyError
             }
-        } else {
-            keys.push(KeyReceived::new(from, key_id, key));
-            Ok(())
-        }
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::AppStateRelay;
-    use crate::config::Config;
+    use super::{AppStateRelay, Hops, ReplayWindow};
+    use crate::config::{Config, Hypercube};
+    use crate::etsi_server::{Key, Share};
+    use crate::health::LinkHealth;
     use crate::relay_server::error::RelayServerError;
+    use crate::store::{KeyStore, MemoryStore};
+    use crate::tls;
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     fn test_config() -> Config {
         let toml = r#"
@@ -96,67 +245,189 @@ kme_address = "http://127.0.0.1:8080"
         toml::from_str(toml).expect("valid config")
     }
 
-    #[test]
-    fn add_key_creates_new_entry_then_increments_counter_on_duplicate() {
+    fn hops() -> Arc<Hops> {
+        Arc::new(Hops::new(
+            tls::relay_client(None).expect("relay client"),
+            None,
+        ))
+    }
+
+    fn test_hypercube() -> Arc<Hypercube> {
+        let toml = r#"
+dimension = 1
+n = 1
+
+[[relay]]
+id = "0"
+pqkds = ["Alice"]
+
+[[connection]]
+first = "Alice"
+second = "Bob"
+"#;
+        Arc::new(toml::from_str(toml).expect("valid hypercube"))
+    }
+
+    fn key(key_id: &str, key: &str) -> Key {
+        Key {
+            key: key.to_string().into(),
+            key_id: key_id.to_string(),
+            extensions: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_key_collects_shares_of_the_same_key() {
         let config = test_config();
-        let key_store = Arc::new(Mutex::new(Vec::new()));
+        let key_store: Arc<dyn KeyStore> = Arc::new(MemoryStore::default());
         let state = AppStateRelay::build(
             config.pqkds().clone(),
             Arc::new(HashMap::new()),
+            hops(),
             HashMap::from([("Alice".to_string(), Arc::clone(&key_store))]),
+            test_hypercube(),
+            LinkHealth::default(),
         );
 
         state
             .add_key(
                 "Alice",
                 "Relay_00".to_string(),
-                "key-1".to_string(),
-                "value-1".to_string(),
+                Share {
+                    index: 0,
+                    total: 2,
+                    threshold: None,
+                },
+                key("key-1", "AQI="),
             )
-            .expect("first save should succeed");
+            .await
+            .expect("first share should succeed");
 
         state
             .add_key(
                 "Alice",
                 "Relay_00".to_string(),
-                "key-1".to_string(),
-                "value-1".to_string(),
+                Share {
+                    index: 1,
+                    total: 2,
+                    threshold: None,
+                },
+                key("key-1", "AwA="),
             )
-            .expect("duplicate same key should increment counter");
+            .await
+            .expect("second share should succeed");
 
-        let stored = key_store.lock().expect("key store lock");
-        assert_eq!(stored.len(), 1);
-        assert_eq!(stored[0].num, 2);
+        assert_eq!(
+            key_store
+                .take_key("Relay_00", "key-1")
+                .expect("take key")
+                .map(|k| k.key),
+            Some("AgI=".to_string().into())
+        );
     }
 
-    #[test]
-    fn add_key_returns_error_when_same_key_id_has_different_payload() {
+    #[tokio::test]
+    async fn add_key_returns_error_when_same_share_has_different_payload() {
         let config = test_config();
-        let key_store = Arc::new(Mutex::new(Vec::new()));
+        let key_store: Arc<dyn KeyStore> = Arc::new(MemoryStore::default());
         let state = AppStateRelay::build(
             config.pqkds().clone(),
             Arc::new(HashMap::new()),
+            hops(),
             HashMap::from([("Alice".to_string(), key_store)]),
+            test_hypercube(),
+            LinkHealth::default(),
         );
 
         state
             .add_key(
                 "Alice",
                 "Relay_00".to_string(),
-                "key-1".to_string(),
-                "value-1".to_string(),
+                Share {
+                    index: 0,
+                    total: 2,
+                    threshold: None,
+                },
+                key("key-1", "AQI="),
             )
+            .await
             .expect("first add should pass");
 
         let err = state
             .add_key(
                 "Alice",
                 "Relay_00".to_string(),
-                "key-1".to_string(),
-                "different".to_string(),
+                Share {
+                    index: 0,
+                    total: 2,
+                    threshold: None,
+                },
+                key("key-1", "AwA="),
             )
+            .await
             .expect_err("mismatch must fail");
 
         assert!(matches!(err, RelayServerError::KeysDoNotMaych));
     }
+
+    #[test]
+    fn replay_window_accepts_each_transfer_once() {
+        let replay = ReplayWindow::new(Duration::from_secs(60));
+        let now = 1_000_000;
+        assert!(replay.claim("t1", now, now).is_ok());
+        assert!(matches!(
+            replay.claim("t1", now, now + 1_000),
+            Err(RelayServerError::ReplayedTransfer(id)) if id == "t1"
+        ));
+        assert!(replay.claim("t2", now - 59_000, now).is_ok());
+
+        // A released claim can be made again.
+        replay.release("t2", now - 59_000);
+        assert!(replay.claim("t2", now - 59_000, now).is_ok());
+
+        // Too old or too far ahead of the local clock.
+        assert!(matches!(
+            replay.claim("t3", now - 61_000, now),
+            Err(RelayServerError::StaleTransfer(_))
+        ));
+        assert!(matches!(
+            replay.claim("t3", now + 61_000, now),
+            Err(RelayServerError::StaleTransfer(_))
+        ));
+        assert!(matches!(
+            replay.claim("", now, now),
+            Err(RelayServerError::InvalidPayload(_))
+        ));
+
+        // Once t1 has left the window it is forgotten, but its timestamp is
+        // then refused anyway.
+        let later = now + 120_000;
+        assert!(replay.claim("t4", later, later).is_ok());
+        assert_eq!(replay.seen.len(), 1);
+        assert!(matches!(
+            replay.claim("t1", now, later),
+            Err(RelayServerError::StaleTransfer(_))
+        ));
+    }
+
+    #[test]
+    fn replay_window_holds_at_most_max_transfers() {
+        let mut replay = ReplayWindow::new(Duration::from_secs(60));
+        replay.max = 2;
+        let now = 1_000_000;
+        assert!(replay.claim("t1", now, now).is_ok());
+        assert!(replay.claim("t2", now, now).is_ok());
+        assert!(matches!(
+            replay.claim("t3", now, now),
+            Err(RelayServerError::TooManyTransfers(id)) if id == "t3"
+        ));
+
+        // Released and expired ids make room again.
+        replay.release("t2", now);
+        assert!(replay.claim("t3", now, now).is_ok());
+        let later = now + 61_000;
+        assert!(replay.claim("t4", later, later).is_ok());
+        assert_eq!(replay.seen.len(), 1);
+        assert_eq!(replay.expiry.lock().expect("lock").len(), 1);
+    }
 }
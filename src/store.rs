@@ -0,0 +1,923 @@
+use crate::etsi_server::{Key, KeyReceived, Share};
+use crate::relay_server::RelayServerError;
+use crate::util;
+use dashmap::DashMap;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+mod cipher;
+
+pub use cipher::RecordCipher;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error(transparent)]
+    Share(#[from] RelayServerError),
+    #[error("key store database error: {0}")]
+    Database(Box<redb::Error>),
+    #[error("key store record error: {0}")]
+    Record(#[from] serde_json::Error),
+    #[error("key-encryption key error: {0}")]
+    Kek(String),
+    #[error("record could not be encrypted")]
+    Encrypt,
+    #[error("record could not be decrypted with any key-encryption key")]
+    Decrypt,
+    #[error("no room for another key from {0}")]
+    Full(String),
+}
+
+fn db<E: Into<redb::Error>>(e: E) -> StoreError {
+    StoreError::Database(Box::new(e.into()))
+}
+
+/// Whether something that arrived at `received_at` is older than `ttl`.
+fn is_past(ttl: Option<Duration>, received_at: u64) -> bool {
+    ttl.is_some_and(|ttl| {
+        u128::from(util::unix_millis().saturating_sub(received_at)) >= ttl.as_millis()
+    })
+}
+
+fn delivered(key_id: String) -> StoreError {
+    StoreError::Share(RelayServerError::KeyDelivered(key_id))
+}
+
+/// Most keys a store holds at a time, in total and per origin SAE.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_keys: usize,
+    pub max_keys_per_origin: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_keys: usize::MAX,
+            max_keys_per_origin: usize::MAX,
+        }
+    }
+}
+
+impl Limits {
+    fn has_room(&self, total: usize, from_origin: usize) -> bool {
+        total < self.max_keys && from_origin < self.max_keys_per_origin
+    }
+}
+
+/// Shares of relayed keys waiting for the local SAE to call `dec_keys`,
+/// indexed by origin SAE and key ID.
+pub trait KeyStore: Send + Sync {
+    /// Records one share of a key, creating the entry on its first share.
+    /// A new entry is refused with [`StoreError::Full`] once a limit is
+    /// reached, after dropping expired keys to make room.
+    fn add_share(&self, from: String, share: Share, key: Key) -> Result<(), StoreError>;
+
+    /// Removes and returns the rebuilt key once enough shares have arrived;
+    /// an incomplete entry is left in place. Expired keys are never returned.
+    /// With a TTL, the key ID of a returned key is remembered until the key
+    /// would have expired, and later shares of it are refused with
+    /// [`RelayServerError::KeyDelivered`]. Without one nothing would ever
+    /// forget it, so delivered key IDs are not remembered.
+    fn take_key(&self, from: &str, key_id: &str) -> Result<Option<Key>, StoreError>;
+
+    /// Drops every key older than the TTL, complete or not, and returns how
+    /// many were dropped. Delivered key IDs past the TTL are forgotten too.
+    fn evict_expired(&self) -> Result<usize, StoreError>;
+
+    /// Number of keys held, only those from `from` when given.
+    fn count(&self, from: Option<&str>) -> Result<usize, StoreError>;
+}
+
+/// Keys held in memory only, lost when the process stops. Entries are
+/// sharded by origin SAE and indexed by key ID, so an operation locks a single
+/// shard and costs the same however many keys are pending.
+#[derive(Default)]
+pub struct MemoryStore {
+    keys: DashMap<String, HashMap<String, KeyReceived>>,
+    /// Origin and key ID of delivered keys, with the arrival of their first
+    /// share.
+    delivered: DashMap<(String, String), u64>,
+    total: AtomicUsize,
+    ttl: Option<Duration>,
+    limits: Limits,
+}
+
+impl MemoryStore {
+    /// Keys expire `ttl` after their first share arrived.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn is_expired(&self, received: &KeyReceived) -> bool {
+        self.ttl.is_some_and(|ttl| received.is_expired(ttl))
+    }
+
+    /// Drops the expired keys of one origin.
+    fn evict(&self, origin: &mut HashMap<String, KeyReceived>) -> usize {
+        let before = origin.len();
+        origin.retain(|_, k| !self.is_expired(k));
+        let evicted = before - origin.len();
+        self.total.fetch_sub(evicted, Ordering::SeqCst);
+        evicted
+    }
+
+    /// Claims room for one more key, if any is left.
+    fn reserve(&self) -> bool {
+        self.total
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                (total < self.limits.max_keys).then_some(total + 1)
+            })
+            .is_ok()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn add_share(&self, from: String, share: Share, key: Key) -> Result<(), StoreError> {
+        let Key {
+            key,
+            key_id,
+            extensions,
+        } = key;
+        // Expired keys of other origins sit in other shards, so they are
+        // dropped before this origin's shard is locked.
+        if self.total.load(Ordering::SeqCst) >= self.limits.max_keys {
+            self.evict_expired()?;
+        }
+        let mut origin = self.keys.entry(from.clone()).or_default();
+        // Checked under the origin's lock, which `take_key` holds while it
+        // records the delivery.
+        let index = (from.clone(), key_id.clone());
+        if let Some(received_at) = self.delivered.get(&index).map(|r| *r) {
+            if !is_past(self.ttl, received_at) {
+                return Err(delivered(key_id));
+            }
+            self.delivered.remove(&index);
+        }
+        if origin.get(&key_id).is_some_and(|k| self.is_expired(k)) {
+            origin.remove(&key_id);
+            self.total.fetch_sub(1, Ordering::SeqCst);
+        }
+        if let Some(received) = origin.get_mut(&key_id) {
+            return Ok(received.add_share(share, key)?);
+        }
+
+        let mut received = KeyReceived::new(from.clone(), key_id.clone(), share);
+        received.extensions = extensions;
+        received.add_share(share, key)?;
+        if origin.len() >= self.limits.max_keys_per_origin {
+            self.evict(&mut origin);
+        }
+        if origin.len() >= self.limits.max_keys_per_origin || !self.reserve() {
+            return Err(StoreError::Full(from));
+        }
+        origin.insert(key_id, received);
+        Ok(())
+    }
+
+    fn take_key(&self, from: &str, key_id: &str) -> Result<Option<Key>, StoreError> {
+        let Some(mut origin) = self.keys.get_mut(from) else {
+            return Ok(None);
+        };
+        let Some(received) = origin.get(key_id) else {
+            return Ok(None);
+        };
+        let key = if self.is_expired(received) {
+            None
+        } else {
+            received.rebuilt()
+        };
+        if key.is_some() && self.ttl.is_some() {
+            self.delivered
+                .insert((from.to_string(), key_id.to_string()), received.received_at);
+        }
+        if key.is_some() || self.is_expired(received) {
+            origin.remove(key_id);
+            self.total.fetch_sub(1, Ordering::SeqCst);
+        }
+        drop(origin);
+        self.keys.remove_if(from, |_, origin| origin.is_empty());
+        Ok(key)
+    }
+
+    fn evict_expired(&self) -> Result<usize, StoreError> {
+        let mut evicted = 0;
+        self.keys.retain(|_, origin| {
+            evicted += self.evict(origin);
+            !origin.is_empty()
+        });
+        self.delivered
+            .retain(|_, received_at| !is_past(self.ttl, *received_at));
+        Ok(evicted)
+    }
+
+    fn count(&self, from: Option<&str>) -> Result<usize, StoreError> {
+        Ok(match from {
+            Some(from) => self.keys.get(from).map_or(0, |origin| origin.len()),
+            None => self.total.load(Ordering::SeqCst),
+        })
+    }
+}
+
+/// Keys kept in an embedded redb file, one table per local SAE. Per-origin
+/// counts and an index by arrival time are kept in tables of their own,
+/// updated in the same transactions, so checking the limits costs the same
+/// however many keys are pending and eviction only visits expired keys. Every share
+/// is committed in its own write transaction, which redb makes durable before
+/// returning, so a crash loses at most the share being written. Commits block
+/// on the disk, so async callers run them with `spawn_blocking`. With a
+/// [`RecordCipher`] every record is encrypted before it reaches the file.
+#[derive(Clone)]
+pub struct FileStore {
+    db: Arc<Database>,
+    cipher: Option<Arc<RecordCipher>>,
+    ttl: Option<Duration>,
+    limits: Limits,
+    table: String,
+    delivered: String,
+    counts: String,
+    arrivals: String,
+}
+
+type Table<'a> = TableDefinition<'a, (&'static str, &'static str), &'static [u8]>;
+/// Origin and key ID of delivered keys, with the arrival of their first share.
+type Delivered<'a> = TableDefinition<'a, (&'static str, &'static str), u64>;
+/// Number of keys held per origin.
+type Counts<'a> = TableDefinition<'a, &'static str, u64>;
+/// Arrival time, origin and key ID of every key held, oldest first.
+type Arrivals<'a> = TableDefinition<'a, (u64, &'static str, &'static str), ()>;
+
+/// The tables of one SAE, opened in a write transaction.
+struct OpenTables<'txn> {
+    keys: redb::Table<'txn, (&'static str, &'static str), &'static [u8]>,
+    counts: redb::Table<'txn, &'static str, u64>,
+    arrivals: redb::Table<'txn, (u64, &'static str, &'static str), ()>,
+}
+
+impl OpenTables<'_> {
+    fn has_room(&self, limits: &Limits, from: &str) -> Result<bool, StoreError> {
+        let total = self.keys.len().map_err(db)? as usize;
+        let from_origin = self.counts.get(from).map_err(db)?.map_or(0, |c| c.value());
+        Ok(limits.has_room(total, from_origin as usize))
+    }
+
+    fn insert(
+        &mut self,
+        from: &str,
+        key_id: &str,
+        received_at: u64,
+        record: &[u8],
+    ) -> Result<(), StoreError> {
+        let count = self.counts.get(from).map_err(db)?.map_or(0, |c| c.value());
+        self.keys.insert((from, key_id), record).map_err(db)?;
+        self.counts.insert(from, count + 1).map_err(db)?;
+        self.arrivals
+            .insert((received_at, from, key_id), ())
+            .map_err(db)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, from: &str, key_id: &str, received_at: u64) -> Result<(), StoreError> {
+        let count = self.counts.get(from).map_err(db)?.map_or(0, |c| c.value());
+        self.keys.remove((from, key_id)).map_err(db)?;
+        if count > 1 {
+            self.counts.insert(from, count - 1).map_err(db)?;
+        } else {
+            self.counts.remove(from).map_err(db)?;
+        }
+        self.arrivals
+            .remove((received_at, from, key_id))
+            .map_err(db)?;
+        Ok(())
+    }
+
+    /// Drops the keys that arrived more than `ttl` ago, oldest first.
+    fn evict(&mut self, ttl: Option<Duration>) -> Result<usize, StoreError> {
+        let mut expired = Vec::new();
+        for entry in self.arrivals.iter().map_err(db)? {
+            let (index, _) = entry.map_err(db)?;
+            let (received_at, from, key_id) = index.value();
+            if !is_past(ttl, received_at) {
+                break;
+            }
+            expired.push((received_at, from.to_string(), key_id.to_string()));
+        }
+        for (received_at, from, key_id) in expired.iter() {
+            self.remove(from, key_id, *received_at)?;
+        }
+        Ok(expired.len())
+    }
+}
+
+impl FileStore {
+    /// Opens the database at `path`, creating it if needed. With a cipher,
+    /// records sealed under a previous key-encryption key or written before
+    /// encryption was enabled are re-encrypted under the current one. Records
+    /// written before arrival times were kept count as arriving now.
+    pub fn open(path: &Path, cipher: Option<RecordCipher>) -> Result<FileStore, StoreError> {
+        let db = Database::create(path).map_err(db)?;
+        let store = FileStore {
+            db: Arc::new(db),
+            cipher: cipher.map(Arc::new),
+            ttl: None,
+            limits: Limits::default(),
+            table: String::new(),
+            delivered: String::new(),
+            counts: String::new(),
+            arrivals: String::new(),
+        };
+        let rewrapped = store.rewrap()?;
+        if rewrapped > 0 {
+            tracing::info!("Re-encrypted {} key store records", rewrapped);
+        }
+        let stamped = store.reindex()?;
+        if stamped > 0 {
+            tracing::info!("Set the arrival time of {} key store records", stamped);
+        }
+        Ok(store)
+    }
+
+    /// The store of `sae_id`, sharing the database file.
+    pub fn for_sae(&self, sae_id: &str) -> FileStore {
+        FileStore {
+            db: Arc::clone(&self.db),
+            cipher: self.cipher.clone(),
+            ttl: self.ttl,
+            limits: self.limits,
+            table: format!("keys/{}", sae_id),
+            delivered: format!("delivered/{}", sae_id),
+            counts: format!("counts/{}", sae_id),
+            arrivals: format!("arrivals/{}", sae_id),
+        }
+    }
+
+    /// Keys expire `ttl` after their first share arrived.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn is_expired(&self, received: &KeyReceived) -> bool {
+        self.ttl.is_some_and(|ttl| received.is_expired(ttl))
+    }
+
+    fn definition(&self) -> Table<'_> {
+        TableDefinition::new(&self.table)
+    }
+
+    fn delivered_definition(&self) -> Delivered<'_> {
+        TableDefinition::new(&self.delivered)
+    }
+
+    fn open_tables<'txn>(
+        &self,
+        txn: &'txn redb::WriteTransaction,
+    ) -> Result<OpenTables<'txn>, StoreError> {
+        Ok(OpenTables {
+            keys: txn.open_table(self.definition()).map_err(db)?,
+            counts: txn.open_table(Counts::new(&self.counts)).map_err(db)?,
+            arrivals: txn.open_table(Arrivals::new(&self.arrivals)).map_err(db)?,
+        })
+    }
+
+    fn encode(&self, received: &KeyReceived) -> Result<Vec<u8>, StoreError> {
+        let mut record = Zeroizing::new(serde_json::to_vec(received)?);
+        match &self.cipher {
+            Some(cipher) => {
+                cipher.seal(&aad(&self.table, &received.from, &received.key_id), &record)
+            }
+            None => Ok(std::mem::take(&mut *record)),
+        }
+    }
+
+    fn decode(&self, from: &str, key_id: &str, record: &[u8]) -> Result<KeyReceived, StoreError> {
+        match &self.cipher {
+            Some(cipher) => {
+                let (plaintext, _) = cipher.open(&aad(&self.table, from, key_id), record)?;
+                Ok(serde_json::from_slice(&plaintext)?)
+            }
+            None => Ok(serde_json::from_slice(record)?),
+        }
+    }
+
+    fn rewrap(&self) -> Result<usize, StoreError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(0);
+        };
+        let txn = self.db.begin_write().map_err(db)?;
+        let names: Vec<String> = txn
+            .list_tables()
+            .map_err(db)?
+            .map(|t| t.name().to_string())
+            .collect();
+        let mut rewrapped = 0;
+        for name in names.iter().filter(|n| n.starts_with("keys/")) {
+            let mut table = txn.open_table(Table::new(name)).map_err(db)?;
+            let mut stale = Vec::new();
+            for entry in table.iter().map_err(db)? {
+                let (index, record) = entry.map_err(db)?;
+                let (from, key_id) = index.value();
+                let aad = aad(name, from, key_id);
+                let plaintext = match cipher.open(&aad, record.value()) {
+                    Ok((_, false)) => continue,
+                    Ok((plaintext, true)) => plaintext,
+                    Err(StoreError::Decrypt)
+                        if serde_json::from_slice::<KeyReceived>(record.value()).is_ok() =>
+                    {
+                        Zeroizing::new(record.value().to_vec())
+                    }
+                    Err(e) => return Err(e),
+                };
+                stale.push((
+                    from.to_string(),
+                    key_id.to_string(),
+                    cipher.seal(&aad, &plaintext)?,
+                ));
+            }
+            for (from, key_id, record) in stale {
+                table
+                    .insert((from.as_str(), key_id.as_str()), record.as_slice())
+                    .map_err(db)?;
+                rewrapped += 1;
+            }
+        }
+        txn.commit().map_err(db)?;
+        Ok(rewrapped)
+    }
+}
+
+impl FileStore {
+    /// Rebuilds the counts and the arrival index of every SAE from its
+    /// records, which files written by earlier versions lack. Records without
+    /// an arrival time are given the current one, so they expire `ttl` from
+    /// now instead of at once. Returns how many were given one.
+    fn reindex(&self) -> Result<usize, StoreError> {
+        let txn = self.db.begin_write().map_err(db)?;
+        let names: Vec<String> = txn
+            .list_tables()
+            .map_err(db)?
+            .map(|t| t.name().to_string())
+            .collect();
+        let now = util::unix_millis();
+        let mut stamped = 0;
+        for name in names.iter() {
+            let Some(sae_id) = name.strip_prefix("keys/") else {
+                continue;
+            };
+            let store = self.for_sae(sae_id);
+            txn.delete_table(Counts::new(&store.counts)).map_err(db)?;
+            txn.delete_table(Arrivals::new(&store.arrivals))
+                .map_err(db)?;
+            let mut records = Vec::new();
+            {
+                let table = txn.open_table(store.definition()).map_err(db)?;
+                for entry in table.iter().map_err(db)? {
+                    let (index, record) = entry.map_err(db)?;
+                    let (from, key_id) = index.value();
+                    let mut received = store.decode(from, key_id, record.value())?;
+                    let record = if received.received_at == 0 {
+                        received.received_at = now;
+                        stamped += 1;
+                        store.encode(&received)?
+                    } else {
+                        record.value().to_vec()
+                    };
+                    records.push((
+                        from.to_string(),
+                        key_id.to_string(),
+                        received.received_at,
+                        record,
+                    ));
+                }
+            }
+            let mut tables = store.open_tables(&txn)?;
+            for (from, key_id, received_at, record) in records.iter() {
+                tables.insert(from, key_id, *received_at, record)?;
+            }
+        }
+        txn.commit().map_err(db)?;
+        Ok(stamped)
+    }
+}
+
+/// Associated data binding a sealed record to its table and index.
+fn aad(table: &str, from: &str, key_id: &str) -> Vec<u8> {
+    format!("{}\0{}\0{}", table, from, key_id).into_bytes()
+}
+
+impl KeyStore for FileStore {
+    fn add_share(&self, from: String, share: Share, key: Key) -> Result<(), StoreError> {
+        let Key {
+            key,
+            key_id,
+            extensions,
+        } = key;
+        let txn = self.db.begin_write().map_err(db)?;
+        {
+            let mut tombstones = txn.open_table(self.delivered_definition()).map_err(db)?;
+            let index = (from.as_str(), key_id.as_str());
+            let received_at = tombstones.get(index).map_err(db)?.map(|v| v.value());
+            if let Some(received_at) = received_at {
+                if !is_past(self.ttl, received_at) {
+                    return Err(delivered(key_id));
+                }
+                tombstones.remove(index).map_err(db)?;
+            }
+            let mut tables = self.open_tables(&txn)?;
+            let stored = tables
+                .keys
+                .get((from.as_str(), key_id.as_str()))
+                .map_err(db)?
+                .map(|v| self.decode(&from, &key_id, v.value()))
+                .transpose()?;
+            let (mut received, new) = match stored {
+                Some(received) if !self.is_expired(&received) => (received, false),
+                stored => {
+                    if let Some(expired) = stored {
+                        tables.remove(&from, &key_id, expired.received_at)?;
+                    }
+                    if !tables.has_room(&self.limits, &from)? {
+                        tables.evict(self.ttl)?;
+                        if !tables.has_room(&self.limits, &from)? {
+                            return Err(StoreError::Full(from));
+                        }
+                    }
+                    let mut received = KeyReceived::new(from.clone(), key_id.clone(), share);
+                    received.extensions = extensions;
+                    (received, true)
+                }
+            };
+            received.add_share(share, key)?;
+            let record = self.encode(&received)?;
+            if new {
+                tables.insert(&from, &key_id, received.received_at, &record)?;
+            } else {
+                tables
+                    .keys
+                    .insert((from.as_str(), key_id.as_str()), record.as_slice())
+                    .map_err(db)?;
+            }
+        }
+        txn.commit().map_err(db)?;
+        Ok(())
+    }
+
+    fn take_key(&self, from: &str, key_id: &str) -> Result<Option<Key>, StoreError> {
+        let txn = self.db.begin_write().map_err(db)?;
+        let key = {
+            let mut tables = self.open_tables(&txn)?;
+            let stored = tables
+                .keys
+                .get((from, key_id))
+                .map_err(db)?
+                .map(|v| self.decode(from, key_id, v.value()))
+                .transpose()?;
+            let key = match stored {
+                Some(received) if self.is_expired(&received) => {
+                    tables.remove(from, key_id, received.received_at)?;
+                    None
+                }
+                Some(received) => {
+                    let key = received.rebuilt();
+                    if key.is_some() {
+                        tables.remove(from, key_id, received.received_at)?;
+                    }
+                    if key.is_some() && self.ttl.is_some() {
+                        txn.open_table(self.delivered_definition())
+                            .map_err(db)?
+                            .insert((from, key_id), received.received_at)
+                            .map_err(db)?;
+                    }
+                    key
+                }
+                None => None,
+            };
+            key
+        };
+        txn.commit().map_err(db)?;
+        Ok(key)
+    }
+
+    fn evict_expired(&self) -> Result<usize, StoreError> {
+        if self.ttl.is_none() {
+            return Ok(0);
+        }
+        let txn = self.db.begin_write().map_err(db)?;
+        let evicted = self.open_tables(&txn)?.evict(self.ttl)?;
+        txn.open_table(self.delivered_definition())
+            .map_err(db)?
+            .retain(|_, received_at| !is_past(self.ttl, received_at))
+            .map_err(db)?;
+        txn.commit().map_err(db)?;
+        Ok(evicted)
+    }
+
+    fn count(&self, from: Option<&str>) -> Result<usize, StoreError> {
+        let txn = self.db.begin_read().map_err(db)?;
+        let count = match from {
+            Some(from) => txn
+                .open_table(Counts::new(&self.counts))
+                .and_then(|counts| Ok(counts.get(from)?.map_or(0, |c| c.value())))
+                .map(|count| count as usize),
+            None => txn
+                .open_table(self.definition())
+                .and_then(|table| Ok(table.len()? as usize)),
+        };
+        match count {
+            Ok(count) => Ok(count),
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(0),
+            Err(e) => Err(db(e)),
+        }
+    }
+}
+
+/// Evicts expired keys from the store of every local SAE each `interval`.
+pub async fn reap(stores: HashMap<String, Arc<dyn KeyStore>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for (sae_id, store) in stores.iter() {
+            let store = Arc::clone(store);
+            match tokio::task::spawn_blocking(move || store.evict_expired()).await {
+                Err(e) => tracing::error!("Key store task failed: {}", e),
+                Ok(Ok(0)) => {}
+                Ok(Ok(evicted)) => {
+                    tracing::warn!("Evicted {} expired keys of SAE {}", evicted, sae_id)
+                }
+                Ok(Err(e)) => tracing::error!("Key store error: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStore, KeyStore, Limits, MemoryStore, RecordCipher, StoreError};
+    use crate::config::Encryption;
+    use crate::etsi_server::{Key, KeyExtensions, Share};
+    use crate::relay_server::RelayServerError;
+    use crate::util;
+    use base64::prelude::*;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn share(index: usize) -> Share {
+        Share {
+            index,
+            total: 2,
+            threshold: None,
+        }
+    }
+
+    fn key(key_id: &str, key: &str) -> Key {
+        Key {
+            key: key.to_string().into(),
+            key_id: key_id.to_string(),
+            extensions: KeyExtensions::default(),
+        }
+    }
+
+    fn fill(store: &dyn KeyStore) {
+        let mut first = key("k1", "AQI=");
+        first.extensions.key_extension = Some(serde_json::json!({ "vendor": 1 }));
+        store
+            .add_share("Bob".into(), share(0), first)
+            .expect("share 0");
+        assert!(store.take_key("Bob", "k1").expect("take").is_none());
+        store
+            .add_share("Bob".into(), share(1), key("k1", "AwA="))
+            .expect("share 1");
+    }
+
+    /// The key `fill` stored, with the extensions of its first share.
+    fn filled(store: &dyn KeyStore) -> Option<(String, Option<serde_json::Value>)> {
+        store
+            .take_key("Bob", "k1")
+            .expect("take")
+            .map(|k| (k.key.to_string(), k.extensions.key_extension))
+    }
+
+    #[test]
+    fn memory_store_hands_out_complete_keys_once() {
+        let store = MemoryStore::default();
+        fill(&store);
+
+        assert_eq!(
+            filled(&store),
+            Some(("AgI=".to_string(), Some(serde_json::json!({ "vendor": 1 }))))
+        );
+        assert!(store.take_key("Bob", "k1").expect("take").is_none());
+    }
+
+    #[test]
+    fn file_store_keeps_keys_across_reopen() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let path = dir.path().join("keys.redb");
+        {
+            let store = FileStore::open(&path, None).expect("open").for_sae("Alice");
+            fill(&store);
+        }
+
+        let reopened = FileStore::open(&path, None).expect("reopen");
+        assert!(reopened
+            .for_sae("Carol")
+            .take_key("Bob", "k1")
+            .expect("take")
+            .is_none());
+        let store = reopened.for_sae("Alice");
+        assert_eq!(
+            filled(&store),
+            Some(("AgI=".to_string(), Some(serde_json::json!({ "vendor": 1 }))))
+        );
+        assert!(store.take_key("Bob", "k1").expect("take").is_none());
+    }
+
+    fn cipher(kek: &Path, previous: &[&Path]) -> RecordCipher {
+        let source = |p: &Path| format!("{{ file = {:?} }}", p);
+        let toml = format!(
+            "kek = {}\nprevious = [{}]",
+            source(kek),
+            previous
+                .iter()
+                .map(|p| source(p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let encryption: Encryption = toml::from_str(&toml).expect("valid encryption");
+        RecordCipher::build(&encryption).expect("cipher")
+    }
+
+    #[test]
+    fn encrypted_file_store_survives_kek_rotation() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let path = dir.path().join("keys.redb");
+        let (old, new) = (dir.path().join("old.kek"), dir.path().join("new.kek"));
+        std::fs::write(&old, "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n").expect("old kek");
+        std::fs::write(&new, "HxwdHhscGRoXGBUWExQREg8QDQ4LDAkKBwgFBgMEAQI=\n").expect("new kek");
+
+        {
+            let store = FileStore::open(&path, Some(cipher(&old, &[]))).expect("open");
+            fill(&store.for_sae("Alice"));
+        }
+        let raw = std::fs::read(&path).expect("read store");
+        assert!(!raw.windows(4).any(|w| w == b"AwA="));
+
+        drop(FileStore::open(&path, Some(cipher(&new, &[&old]))).expect("rotate"));
+        assert!(matches!(
+            FileStore::open(&path, Some(cipher(&old, &[]))),
+            Err(StoreError::Decrypt)
+        ));
+
+        let store = FileStore::open(&path, Some(cipher(&new, &[])))
+            .expect("reopen")
+            .for_sae("Alice");
+        assert_eq!(
+            filled(&store),
+            Some(("AgI=".to_string(), Some(serde_json::json!({ "vendor": 1 }))))
+        );
+    }
+
+    #[test]
+    fn records_without_arrival_time_are_stamped_on_open() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let path = dir.path().join("keys.redb");
+        let kek = dir.path().join("kek");
+        std::fs::write(&kek, "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n").expect("kek");
+        {
+            let db = redb::Database::create(&path).expect("create");
+            let txn = db.begin_write().expect("write");
+            txn.open_table(super::Table::new("keys/Alice"))
+                .expect("table")
+                .insert(
+                    ("Bob", "k1"),
+                    br#"{"from":"Bob","key_id":"k1","total":1,"threshold":null,"shares":[[0,"AQI="]]}"#
+                        .as_slice(),
+                )
+                .expect("insert");
+            txn.commit().expect("commit");
+        }
+
+        let store = FileStore::open(&path, Some(cipher(&kek, &[])))
+            .expect("open")
+            .with_ttl(Duration::from_secs(60))
+            .for_sae("Alice");
+        assert_eq!(store.evict_expired().expect("evict"), 0);
+        assert_eq!(store.count(Some("Bob")).expect("count"), 1);
+        assert_eq!(
+            store.take_key("Bob", "k1").expect("take").map(|k| k.key),
+            Some("AQI=".to_string().into())
+        );
+        assert_eq!(store.count(Some("Bob")).expect("count"), 0);
+    }
+
+    #[test]
+    fn expired_keys_are_never_handed_out() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let file = FileStore::open(&dir.path().join("keys.redb"), None)
+            .expect("open")
+            .with_ttl(Duration::ZERO)
+            .for_sae("Alice");
+        let memory = MemoryStore::default().with_ttl(Duration::ZERO);
+
+        for store in [&file as &dyn KeyStore, &memory] {
+            fill(store);
+            assert!(store.take_key("Bob", "k1").expect("take").is_none());
+
+            store
+                .add_share("Bob".into(), share(0), key("k2", "AQI="))
+                .expect("share 0");
+            assert_eq!(store.evict_expired().expect("evict"), 1);
+            assert_eq!(store.evict_expired().expect("evict"), 0);
+        }
+    }
+
+    #[test]
+    fn late_shares_of_delivered_keys_are_refused() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let file = FileStore::open(&dir.path().join("keys.redb"), None)
+            .expect("open")
+            .with_ttl(Duration::from_secs(60))
+            .for_sae("Alice");
+        let memory = MemoryStore::default().with_ttl(Duration::from_secs(60));
+        let secret = b"relayed".to_vec();
+        let shares = util::shamir_split(&secret, 2, 3);
+        let add = |store: &dyn KeyStore, index: usize| {
+            let share = Share {
+                index,
+                total: 3,
+                threshold: Some(2),
+            };
+            store.add_share(
+                "Bob".into(),
+                share,
+                key("k1", &BASE64_STANDARD.encode(&shares[index])),
+            )
+        };
+
+        for store in [&file as &dyn KeyStore, &memory] {
+            add(store, 0).expect("share 0");
+            add(store, 1).expect("share 1");
+            let taken = store.take_key("Bob", "k1").expect("take").expect("key");
+            assert_eq!(*taken.key, BASE64_STANDARD.encode(&secret));
+
+            assert!(matches!(
+                add(store, 2),
+                Err(StoreError::Share(RelayServerError::KeyDelivered(id))) if id == "k1"
+            ));
+            assert!(store.take_key("Bob", "k1").expect("take").is_none());
+            assert_eq!(store.count(None).expect("count"), 0);
+        }
+
+        // Without a TTL no delivered key ID is kept, as none would expire.
+        let forever = MemoryStore::default();
+        add(&forever, 0).expect("share 0");
+        add(&forever, 1).expect("share 1");
+        assert!(forever.take_key("Bob", "k1").expect("take").is_some());
+        assert!(forever.delivered.is_empty());
+    }
+
+    #[test]
+    fn full_stores_refuse_new_keys_but_accept_missing_shares() {
+        let limits = Limits {
+            max_keys: 3,
+            max_keys_per_origin: 2,
+        };
+        let dir = tempfile::tempdir().expect("temp dir");
+        let file = FileStore::open(&dir.path().join("keys.redb"), None)
+            .expect("open")
+            .with_limits(limits)
+            .for_sae("Alice");
+        let memory = MemoryStore::default().with_limits(limits);
+
+        for store in [&file as &dyn KeyStore, &memory] {
+            let add = |from: &str, key_id: &str, index| {
+                store.add_share(from.into(), share(index), key(key_id, "AQI="))
+            };
+            add("Bob", "k1", 0).expect("k1");
+            add("Bob", "k2", 0).expect("k2");
+            assert!(matches!(add("Bob", "k3", 0), Err(StoreError::Full(_))));
+            add("Bob", "k1", 1).expect("second share of k1");
+            add("Carol", "k1", 0).expect("other origin");
+            assert!(matches!(add("Dave", "k1", 0), Err(StoreError::Full(_))));
+
+            assert_eq!(store.count(Some("Bob")).expect("count"), 2);
+            assert_eq!(store.count(None).expect("count"), 3);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use super::StoreError;
+use crate::config::{Encryption, KekSource};
+use base64::prelude::*;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::{env, fs};
+use zeroize::Zeroizing;
+
+const NONCE_LEN: usize = 24;
+
+/// Seals key store records with XChaCha20-Poly1305 under a key-encryption
+/// key. A record is the random nonce followed by the ciphertext; the record's
+/// table and index are bound in as associated data so records cannot be
+/// swapped around on disk.
+pub struct RecordCipher {
+    current: XChaCha20Poly1305,
+    previous: Vec<XChaCha20Poly1305>,
+}
+
+impl RecordCipher {
+    pub fn build(encryption: &Encryption) -> Result<RecordCipher, StoreError> {
+        Ok(RecordCipher {
+            current: load(encryption.kek())?,
+            previous: encryption
+                .previous()
+                .iter()
+                .map(load)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .current
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| StoreError::Encrypt)?;
+        let mut record = nonce.to_vec();
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    /// The plaintext of `record`, and whether it was sealed under one of the
+    /// previous keys and so needs sealing again.
+    pub fn open(
+        &self,
+        aad: &[u8],
+        record: &[u8],
+    ) -> Result<(Zeroizing<Vec<u8>>, bool), StoreError> {
+        if record.len() < NONCE_LEN {
+            return Err(StoreError::Decrypt);
+        }
+        let (nonce, msg) = record.split_at(NONCE_LEN);
+        let nonce = XNonce::from_slice(nonce);
+        for (i, cipher) in std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .enumerate()
+        {
+            if let Ok(plaintext) = cipher.decrypt(nonce, Payload { msg, aad }) {
+                return Ok((Zeroizing::new(plaintext), i > 0));
+            }
+        }
+        Err(StoreError::Decrypt)
+    }
+}
+
+fn load(source: &KekSource) -> Result<XChaCha20Poly1305, StoreError> {
+    let encoded = Zeroizing::new(match (source.file(), source.env()) {
+        (Some(file), _) => fs::read_to_string(file)
+            .map_err(|e| StoreError::Kek(format!("cannot read {}: {}", source, e)))?,
+        (None, Some(var)) => {
+            env::var(var).map_err(|e| StoreError::Kek(format!("cannot read {}: {}", source, e)))?
+        }
+        (None, None) => return Err(StoreError::Kek("no file or env given".to_string())),
+    });
+    let kek = Zeroizing::new(
+        BASE64_STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| StoreError::Kek(format!("{} is not base64: {}", source, e)))?,
+    );
+    XChaCha20Poly1305::new_from_slice(&kek)
+        .map_err(|_| StoreError::Kek(format!("{} must hold 32 bytes", source)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecordCipher;
+    use crate::config::Encryption;
+    use crate::store::StoreError;
+
+    #[test]
+    fn sealed_records_open_only_with_the_same_associated_data() {
+        std::env::set_var(
+            "CIPHER_TEST_KEK",
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+        );
+        let encryption: Encryption =
+            toml::from_str("kek = { env = \"CIPHER_TEST_KEK\" }").expect("valid encryption");
+        let cipher = RecordCipher::build(&encryption).expect("cipher");
+
+        let record = cipher
+            .seal(b"keys/Alice\0Bob\0k1", b"secret")
+            .expect("seal");
+        assert!(!record.windows(6).any(|w| w == b"secret"));
+        let (plaintext, stale) = cipher.open(b"keys/Alice\0Bob\0k1", &record).expect("open");
+        assert_eq!(plaintext.as_slice(), b"secret");
+        assert!(!stale);
+
+        assert!(matches!(
+            cipher.open(b"keys/Alice\0Bob\0k2", &record),
+            Err(StoreError::Decrypt)
+        ));
+    }
+}
@@ -0,0 +1,519 @@
+use crate::config::ServerTls;
+use axum::{body::Body, http::uri::Scheme, Router};
+use hyper::body::Incoming;
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::crypto::{aws_lc_rs, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, InvalidDnsNameError, PrivateKeyDer, ServerName,
+};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{
+    version, ClientConfig, RootCertStore, ServerConfig, SupportedProtocolVersion, DEFAULT_VERSIONS,
+};
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+use tower::{Service, ServiceExt};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("rustls error: {0}")]
+    RustlsError(#[from] tokio_rustls::rustls::Error),
+    #[error("client verifier error: {0}")]
+    VerifierError(#[from] tokio_rustls::rustls::server::VerifierBuilderError),
+    #[error("invalid server name: {0}")]
+    ServerNameError(#[from] InvalidDnsNameError),
+    #[error("no {0} in {1}")]
+    MissingPem(&'static str, String),
+}
+
+/// Client for `/info_keys` of neighbouring relays.
+pub type RelayClient = hyper_util::client::legacy::Client<RelayConnector, Body>;
+
+/// Id of the client at the other end of a TLS connection, taken from its
+/// certificate: an SAE id on an ETSI façade, a relay id on the relay
+/// endpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer(pub String);
+
+/// Crypto of `tls`. aws-lc-rs offers the hybrid X25519MLKEM768 group first
+/// and falls back to classical groups, unless `post_quantum` leaves only the
+/// hybrid one. ML-DSA certificates are accepted either way.
+fn provider(tls: &ServerTls) -> Arc<CryptoProvider> {
+    let mut provider = aws_lc_rs::default_provider();
+    if tls.post_quantum() {
+        provider.kx_groups = vec![aws_lc_rs::kx_group::X25519MLKEM768];
+    }
+    Arc::new(provider)
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+fn versions(tls: &ServerTls) -> &'static [&'static SupportedProtocolVersion] {
+    if tls.post_quantum() {
+        TLS13_ONLY
+    } else {
+        DEFAULT_VERSIONS
+    }
+}
+
+fn roots(tls: &ServerTls) -> Result<Arc<RootCertStore>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(tls.client_ca())? {
+        roots.add(cert)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+pub fn acceptor(tls: &ServerTls) -> Result<TlsAcceptor, TlsError> {
+    let provider = provider(tls);
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(roots(tls)?, Arc::clone(&provider)).build()?;
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions(tls))?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs(tls.cert())?, key(tls.key())?)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Trust anchors of the system, read from the bundle and directories
+/// openssl would use. Unreadable files and certificates are skipped.
+fn system_roots() -> Arc<RootCertStore> {
+    let probe = openssl_probe::probe();
+    let mut files: Vec<_> = probe.cert_file.into_iter().collect();
+    for dir in probe.cert_dir {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            files.extend(entries.flatten().map(|entry| entry.path()));
+        }
+    }
+    let mut roots = RootCertStore::empty();
+    for file in files {
+        if let Ok(pem) = std::fs::read(&file) {
+            roots.add_parsable_certificates(rustls_pemfile::certs(&mut pem.as_slice()).flatten());
+        }
+    }
+    if roots.is_empty() {
+        tracing::warn!("no system root certificates found, https relays will fail to verify");
+    }
+    Arc::new(roots)
+}
+
+/// Client presenting the certificate of `tls` to `https://` relays and
+/// trusting those issued by its `client_ca`. Without `tls` it presents no
+/// certificate and verifies `https://` relays against the system roots.
+pub fn relay_client(tls: Option<&ServerTls>) -> Result<RelayClient, TlsError> {
+    let mut config = match tls {
+        Some(tls) => ClientConfig::builder_with_provider(provider(tls))
+            .with_protocol_versions(versions(tls))?
+            .with_root_certificates(roots(tls)?)
+            .with_client_auth_cert(certs(tls.cert())?, key(tls.key())?)?,
+        None => ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(system_roots())
+            .with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let tls = TlsConnector::from(Arc::new(config));
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    Ok(
+        hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+            .http1_title_case_headers(true)
+            .build(RelayConnector { http, tls }),
+    )
+}
+
+/// Connector of [`RelayClient`]: TLS with rustls for `https://` addresses,
+/// plain TCP otherwise.
+#[derive(Clone)]
+pub struct RelayConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+}
+
+impl Service<Uri> for RelayConnector {
+    type Response = TokioIo<RelayStream>;
+    type Error = TlsError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, TlsError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), TlsError>> {
+        self.http
+            .poll_ready(cx)
+            .map_err(|e| std::io::Error::other(e).into())
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let tls = (uri.scheme() == Some(&Scheme::HTTPS)).then(|| self.tls.clone());
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let connecting = self.http.call(uri);
+        Box::pin(async move {
+            let tcp = connecting
+                .await
+                .map_err(std::io::Error::other)?
+                .into_inner();
+            let stream = match tls {
+                Some(tls) => {
+                    let name = ServerName::try_from(host)?;
+                    RelayStream::Tls(Box::new(tls.connect(name, tcp).await?))
+                }
+                None => RelayStream::Plain(tcp),
+            };
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+pub enum RelayStream {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl Connection for RelayStream {
+    fn connected(&self) -> Connected {
+        match self {
+            RelayStream::Plain(tcp) => tcp.connected(),
+            RelayStream::Tls(tls) => tls.get_ref().0.connected(),
+        }
+    }
+}
+
+impl AsyncRead for RelayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RelayStream::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            RelayStream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RelayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            RelayStream::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            RelayStream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RelayStream::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            RelayStream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RelayStream::Plain(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            RelayStream::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
+
+fn certs(path: &std::path::Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem = std::fs::read(path)?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::MissingPem(
+            "certificate",
+            path.display().to_string(),
+        ));
+    }
+    Ok(certs)
+}
+
+fn key(path: &std::path::Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let pem = std::fs::read(path)?;
+    rustls_pemfile::private_key(&mut pem.as_slice())?
+        .ok_or_else(|| TlsError::MissingPem("private key", path.display().to_string()))
+}
+
+/// Identity a client certificate names: its subject common name, or else its
+/// first DNS name.
+pub fn identity(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+    {
+        return Some(cn.to_string());
+    }
+    cert.subject_alternative_name()
+        .ok()??
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::DNSName(dns) => Some(dns.to_string()),
+            _ => None,
+        })
+}
+
+/// Longest a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves `app` over TLS, handing every request the [`Peer`] its connection
+/// authenticated as. Connections without a usable client certificate are
+/// dropped. So are clients that stall the handshake for longer than
+/// [`HANDSHAKE_TIMEOUT`].
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    tls: ServerTls,
+    app: Router,
+) -> Result<(), std::io::Error> {
+    let tls = Arc::new(tls);
+    loop {
+        // Like `axum::serve`, ride out errors such as running out of file
+        // descriptors instead of shutting the listener down.
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("Accepting a connection failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let tls = Arc::clone(&tls);
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        tracing::warn!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::warn!("TLS handshake with {} timed out", addr);
+                        return;
+                    }
+                };
+            let Some(identity) = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(identity)
+            else {
+                tracing::warn!("Client certificate of {} names no identity", addr);
+                return;
+            };
+            let peer = Peer(tls.peer_id(&identity).to_string());
+            tracing::debug!("{} authenticated as {}", addr, peer.0);
+
+            let service = hyper::service::service_fn(move |mut req: hyper::Request<Incoming>| {
+                req.extensions_mut().insert(peer.clone());
+                app.clone().oneshot(req.map(Body::new))
+            });
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Connection with {} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{acceptor, relay_client, serve};
+    use crate::config::ServerTls;
+    use axum::{body::Body, routing::post, Router};
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::crypto::{aws_lc_rs, CryptoProvider};
+    use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::{ClientConfig, NamedGroup, RootCertStore};
+    use tokio_rustls::{client, TlsConnector};
+
+    /// A CA issuing the server and client certificates of a test.
+    pub struct Pki {
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl Pki {
+        pub fn new() -> Self {
+            let ca_key = KeyPair::generate().expect("key");
+            let mut params = CertificateParams::new(Vec::new()).expect("params");
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "Test CA");
+            let ca = params.self_signed(&ca_key).expect("ca");
+            Pki { ca, ca_key }
+        }
+
+        /// PEM certificate and key for `name`, a DNS name for the server or
+        /// the common name of a client.
+        fn issue(&self, name: &str, client: bool) -> (String, String) {
+            let key = KeyPair::generate().expect("key");
+            let mut params = if client {
+                let mut params = CertificateParams::new(Vec::new()).expect("params");
+                params.distinguished_name.push(DnType::CommonName, name);
+                params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+                params
+            } else {
+                CertificateParams::new(vec![name.to_string()]).expect("params")
+            };
+            params.is_ca = IsCa::NoCa;
+            let cert = params
+                .signed_by(&key, &self.ca, &self.ca_key)
+                .expect("cert");
+            (cert.pem(), key.serialize_pem())
+        }
+
+        /// TLS of `name` written to `dir`, trusting this CA for peers, with
+        /// `extra` TOML lines.
+        fn tls(&self, dir: &Path, name: &str, client: bool, extra: &str) -> ServerTls {
+            let (cert, key) = self.issue(name, client);
+            let (cert_path, key_path) = (
+                dir.join(format!("{name}.pem")),
+                dir.join(format!("{name}.key")),
+            );
+            std::fs::write(&cert_path, cert).expect("write");
+            std::fs::write(&key_path, key).expect("write");
+            std::fs::write(dir.join("ca.pem"), self.ca.pem()).expect("write");
+            toml::from_str(&format!(
+                "cert = {:?}\nkey = {:?}\nclient_ca = {:?}\n{}",
+                cert_path,
+                key_path,
+                dir.join("ca.pem"),
+                extra
+            ))
+            .expect("valid tls")
+        }
+
+        /// Server TLS for `localhost`.
+        pub fn server_tls(&self, dir: &Path, extra: &str) -> ServerTls {
+            self.tls(dir, "localhost", false, extra)
+        }
+
+        /// TLS of the client `name`.
+        pub fn client_tls(&self, dir: &Path, name: &str, extra: &str) -> ServerTls {
+            self.tls(dir, name, true, extra)
+        }
+
+        /// TLS connection to `port` as the client `name`, offering the key
+        /// exchange groups of `provider`.
+        pub async fn connect_as(
+            &self,
+            port: u16,
+            name: &str,
+            provider: CryptoProvider,
+        ) -> std::io::Result<client::TlsStream<tokio::net::TcpStream>> {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(CertificateDer::from(self.ca.der().to_vec()))
+                .expect("root");
+            let (cert, key) = self.issue(name, true);
+            let config = ClientConfig::builder_with_provider(Arc::new(provider))
+                .with_safe_default_protocol_versions()
+                .expect("versions")
+                .with_root_certificates(roots)
+                .with_client_auth_cert(
+                    vec![CertificateDer::from_pem_slice(cert.as_bytes()).expect("cert")],
+                    PrivateKeyDer::from_pem_slice(key.as_bytes()).expect("key"),
+                )
+                .expect("client config");
+            let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+            TlsConnector::from(Arc::new(config))
+                .connect("localhost".try_into().expect("name"), stream)
+                .await
+        }
+
+        /// Status line of the response to `request` sent as the client
+        /// `name`.
+        pub async fn send_as(&self, port: u16, name: &str, request: &str) -> String {
+            let mut stream = self
+                .connect_as(port, name, aws_lc_rs::default_provider())
+                .await
+                .expect("handshake");
+            stream.write_all(request.as_bytes()).await.expect("write");
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response).await;
+            response.lines().next().unwrap_or_default().to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn post_quantum_relays_only_agree_hybrid_keys() {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let pki = Pki::new();
+        let server = pki.server_tls(dir.path(), "post_quantum = true");
+        let app = Router::new().route("/info_keys", post(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        let acceptor = acceptor(&server).expect("acceptor");
+        tokio::spawn(serve(listener, acceptor, server, app));
+
+        let stream = pki
+            .connect_as(port, "01", aws_lc_rs::default_provider())
+            .await
+            .expect("hybrid handshake");
+        assert_eq!(
+            stream
+                .get_ref()
+                .1
+                .negotiated_key_exchange_group()
+                .map(|g| g.name()),
+            Some(NamedGroup::X25519MLKEM768)
+        );
+
+        let mut classical = aws_lc_rs::default_provider();
+        classical.kx_groups = vec![aws_lc_rs::kx_group::X25519];
+        assert!(pki.connect_as(port, "01", classical).await.is_err());
+
+        let client = relay_client(Some(&pki.client_tls(
+            dir.path(),
+            "01",
+            "post_quantum = true",
+        )))
+        .expect("relay client");
+        let request = hyper::Request::post(format!("https://localhost:{}/info_keys", port))
+            .body(Body::empty())
+            .expect("request");
+        let response = client.request(request).await.expect("response");
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+
+        let plain = relay_client(None).expect("relay client");
+        let request = hyper::Request::post(format!("https://localhost:{}/info_keys", port))
+            .body(Body::empty())
+            .expect("request");
+        assert!(plain.request(request).await.is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::config::Hypercube;
+use crate::health::LinkHealth;
+use petgraph::graph::{NodeIndex, UnGraph};
+use petgraph::visit::EdgeRef;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Dot,
+    Mermaid,
+    #[default]
+    Json,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Node {
+    Relay { id: String },
+    Sae { id: String },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Edge {
+    /// The relay hosts the SAE.
+    Hosts,
+    /// QKD link between two SAEs, with the indexes of the highlighted paths
+    /// crossing it.
+    Connection { paths: Vec<usize> },
+}
+
+/// Relays, their SAEs and the QKD links of a topology, plus the SAE hop lists
+/// of the paths chosen between two SAEs.
+#[derive(Serialize, Debug)]
+pub struct Topology {
+    graph: UnGraph<Node, Edge>,
+    paths: Vec<Vec<String>>,
+}
+
+impl Topology {
+    /// Builds the topology and, when `route` is given, highlights the paths
+    /// that would carry keys from the first SAE to the second.
+    pub fn build(hypercube: &Hypercube, route: Option<(&str, &str)>, health: &LinkHealth) -> Self {
+        let mut graph = UnGraph::new_undirected();
+        let mut saes: HashMap<String, NodeIndex> = HashMap::new();
+
+        for relay in hypercube.relay() {
+            let r = graph.add_node(Node::Relay {
+                id: relay.id().to_string(),
+            });
+            for sae_id in relay.pqkds() {
+                let s = *saes
+                    .entry(sae_id.clone())
+                    .or_insert_with(|| graph.add_node(Node::Sae { id: sae_id.clone() }));
+                graph.add_edge(r, s, Edge::Hosts);
+            }
+        }
+        for con in hypercube.connection() {
+            let mut sae = |id: &str| {
+                *saes
+                    .entry(id.to_string())
+                    .or_insert_with(|| graph.add_node(Node::Sae { id: id.to_string() }))
+            };
+            let (a, b) = (sae(con.first()), sae(con.second()));
+            graph.add_edge(a, b, Edge::Connection { paths: Vec::new() });
+        }
+
+        let paths = match route {
+            Some((source, target)) => chosen_paths(hypercube, source, target, health),
+            None => Vec::new(),
+        };
+        for (i, path) in paths.iter().enumerate() {
+            for hop in path.windows(2) {
+                let (Some(&a), Some(&b)) = (saes.get(&hop[0]), saes.get(&hop[1])) else {
+                    continue;
+                };
+                if let Some(e) = graph.find_edge(a, b) {
+                    if let Edge::Connection { paths } = &mut graph[e] {
+                        paths.push(i);
+                    }
+                }
+            }
+        }
+
+        Topology { graph, paths }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Dot => self.to_dot(),
+            Format::Mermaid => self.to_mermaid(),
+            Format::Json => serde_json::to_string_pretty(self).unwrap_or_default(),
+        }
+    }
+
+    fn label(&self, node: NodeIndex) -> &str {
+        match &self.graph[node] {
+            Node::Relay { id } | Node::Sae { id } => id,
+        }
+    }
+
+    fn hosted(&self, relay: NodeIndex) -> Vec<&str> {
+        let mut saes: Vec<&str> = self
+            .graph
+            .edges(relay)
+            .filter(|e| *e.weight() == Edge::Hosts)
+            .map(|e| self.label(e.target()))
+            .collect();
+        saes.sort();
+        saes
+    }
+
+    fn connections(&self) -> impl Iterator<Item = (&str, &str, &Vec<usize>)> {
+        self.graph
+            .edge_references()
+            .filter_map(|e| match e.weight() {
+                Edge::Connection { paths } => {
+                    Some((self.label(e.source()), self.label(e.target()), paths))
+                }
+                Edge::Hosts => None,
+            })
+    }
+
+    fn to_dot(&self) -> String {
+        let mut out = String::from("graph topology {\n");
+        for relay in self.graph.node_indices() {
+            if let Node::Relay { id } = &self.graph[relay] {
+                let _ = writeln!(out, "  subgraph \"cluster_{}\" {{", id);
+                let _ = writeln!(out, "    label = \"Relay {}\";", id);
+                for sae_id in self.hosted(relay) {
+                    let _ = writeln!(out, "    \"{}\";", sae_id);
+                }
+                out.push_str("  }\n");
+            }
+        }
+        for (a, b, paths) in self.connections() {
+            if paths.is_empty() {
+                let _ = writeln!(out, "  \"{}\" -- \"{}\";", a, b);
+            } else {
+                let _ = writeln!(
+                    out,
+                    "  \"{}\" -- \"{}\" [color = red, penwidth = 2, label = \"{}\"];",
+                    a,
+                    b,
+                    path_labels(paths)
+                );
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn to_mermaid(&self) -> String {
+        let mut out = String::from("flowchart LR\n");
+        for relay in self.graph.node_indices() {
+            if let Node::Relay { id } = &self.graph[relay] {
+                let _ = writeln!(out, "  subgraph relay_{}[\"Relay {}\"]", id, id);
+                for sae_id in self.hosted(relay) {
+                    let _ = writeln!(out, "    {}", sae_id);
+                }
+                out.push_str("  end\n");
+            }
+        }
+        let mut highlighted = Vec::new();
+        for (i, (a, b, paths)) in self.connections().enumerate() {
+            if paths.is_empty() {
+                let _ = writeln!(out, "  {} --- {}", a, b);
+            } else {
+                let _ = writeln!(out, "  {} ---|\"{}\"| {}", a, path_labels(paths), b);
+                highlighted.push(i.to_string());
+            }
+        }
+        if !highlighted.is_empty() {
+            let _ = writeln!(
+                out,
+                "  linkStyle {} stroke:red,stroke-width:3px",
+                highlighted.join(",")
+            );
+        }
+        out
+    }
+}
+
+fn path_labels(paths: &[usize]) -> String {
+    paths
+        .iter()
+        .map(|i| format!("path {}", i + 1))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// SAE hop lists of the paths `_enc_keys` would pick from `source` to `target`.
+fn chosen_paths(
+    hypercube: &Hypercube,
+    source: &str,
+    target: &str,
+    health: &LinkHealth,
+) -> Vec<Vec<String>> {
+    let (Some(start), Some(end)) = (hypercube.find_relay(source), hypercube.find_relay(target))
+    else {
+        return Vec::new();
+    };
+    hypercube
+        .find_paths(start, end, health)
+        .iter()
+        .filter_map(|p| hypercube.sae_path(p, source, target, health))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Topology};
+    use crate::config::Hypercube;
+    use crate::health::LinkHealth;
+
+    fn test_hypercube() -> Hypercube {
+        let toml = r#"
+dimension = 1
+n = 1
+
+[[relay]]
+id = "0"
+pqkds = ["Alice", "A1"]
+
+[[relay]]
+id = "1"
+pqkds = ["Bob", "B1"]
+
+[[connection]]
+first = "A1"
+second = "B1"
+
+[[connection]]
+first = "Alice"
+second = "Carol"
+"#;
+        toml::from_str(toml).expect("valid hypercube")
+    }
+
+    #[test]
+    fn dot_export_groups_saes_per_relay_and_highlights_route() {
+        let topology = Topology::build(
+            &test_hypercube(),
+            Some(("Alice", "Bob")),
+            &LinkHealth::default(),
+        );
+        let dot = topology.render(Format::Dot);
+
+        assert!(dot.starts_with("graph topology {"));
+        assert!(dot.contains(
+            "subgraph \"cluster_0\" {\n    label = \"Relay 0\";\n    \"A1\";\n    \"Alice\";\n  }"
+        ));
+        assert!(dot.contains("\"A1\" -- \"B1\" [color = red, penwidth = 2, label = \"path 1\"];"));
+        assert!(dot.contains("\"Alice\" -- \"Carol\";"));
+    }
+
+    #[test]
+    fn mermaid_export_styles_highlighted_links() {
+        let topology = Topology::build(
+            &test_hypercube(),
+            Some(("Alice", "Bob")),
+            &LinkHealth::default(),
+        );
+        let mermaid = topology.render(Format::Mermaid);
+
+        assert!(mermaid.starts_with("flowchart LR\n"));
+        assert!(mermaid.contains("  subgraph relay_1[\"Relay 1\"]\n    B1\n    Bob\n  end"));
+        assert!(mermaid.contains("  A1 ---|\"path 1\"| B1"));
+        assert!(mermaid.contains("  Alice --- Carol"));
+        assert!(mermaid.contains("  linkStyle 0 stroke:red,stroke-width:3px"));
+    }
+
+    #[test]
+    fn json_export_lists_nodes_edges_and_paths() {
+        let topology = Topology::build(
+            &test_hypercube(),
+            Some(("Alice", "Bob")),
+            &LinkHealth::default(),
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&topology.render(Format::Json)).expect("valid json");
+
+        assert_eq!(json["graph"]["nodes"].as_array().map(Vec::len), Some(7));
+        assert_eq!(
+            json["paths"],
+            serde_json::json!([["Alice", "A1", "B1", "Bob"]])
+        );
+        assert!(json["graph"]["edges"]
+            .as_array()
+            .expect("edges")
+            .iter()
+            .any(|e| e[2] == serde_json::json!({"kind": "connection", "paths": [0]})));
+    }
+}
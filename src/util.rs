@@ -1,11 +1,145 @@
+use poly1305::universal_hash::{KeyInit, UniversalHash};
+use poly1305::Poly1305;
+use rand::RngCore;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 pub fn xor(a: Vec<u8>, b: Vec<u8>) -> Vec<u8> {
     let c = a.iter().zip(b.iter()).map(|(&x1, &x2)| x1 ^ x2).collect();
     c
 }
 
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Splits `secret` into `n` shares whose XOR is `secret`. All shares but the
+/// last are uniformly random, so any `n - 1` of them reveal nothing.
+pub fn xor_split(secret: &[u8], n: usize) -> Vec<Vec<u8>> {
+    let mut shares: Vec<Vec<u8>> = (1..n).map(|_| random_bytes(secret.len())).collect();
+    if n > 0 {
+        let last = shares
+            .iter()
+            .fold(secret.to_vec(), |acc, share| xor(acc, share.clone()));
+        shares.push(last);
+    }
+    shares
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    p
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 == a^-1 for every non-zero a.
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Splits `secret` into `n` Shamir shares over GF(2^8), any `k` of which
+/// rebuild it. Share `i` is the polynomial evaluated at `x = i + 1`.
+pub fn shamir_split(secret: &[u8], k: usize, n: usize) -> Vec<Vec<u8>> {
+    let coefficients: Vec<Vec<u8>> = (1..k).map(|_| random_bytes(secret.len())).collect();
+    (1..=n)
+        .map(|x| {
+            let x = x as u8;
+            secret
+                .iter()
+                .enumerate()
+                .map(|(b, &s)| {
+                    // Horner's rule, highest coefficient first.
+                    let high = coefficients
+                        .iter()
+                        .rev()
+                        .fold(0u8, |acc, c| gf_mul(acc, x) ^ c[b]);
+                    gf_mul(high, x) ^ s
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Lagrange interpolation of the shares `(x, y)` evaluated at `x`; `x = 0`
+/// yields the secret.
+pub fn shamir_interpolate(points: &[(u8, Vec<u8>)], x: u8) -> Vec<u8> {
+    let len = points.iter().map(|(_, y)| y.len()).min().unwrap_or(0);
+    let mut result = vec![0u8; len];
+    for (j, (xj, yj)) in points.iter().enumerate() {
+        let mut basis = 1u8;
+        for (m, (xm, _)) in points.iter().enumerate() {
+            if m != j {
+                basis = gf_mul(basis, gf_mul(x ^ xm, gf_inv(xj ^ xm)));
+            }
+        }
+        for (r, y) in result.iter_mut().zip(yj.iter()) {
+            *r ^= gf_mul(basis, *y);
+        }
+    }
+    result
+}
+
+/// Bytes of a one-time MAC key: the Poly1305 `r` and `s` halves.
+pub const MAC_KEY_LEN: usize = 32;
+
+/// Wegman-Carter tag of `message` under a one-time `key` of [`MAC_KEY_LEN`]
+/// bytes: a Poly1305 polynomial hash masked with the key's second half. A
+/// uniformly random key used once makes forging the tag as hard as guessing
+/// it. `None` for a key of the wrong length.
+pub fn mac(key: &[u8], message: &[u8]) -> Option<[u8; 16]> {
+    let key = poly1305::Key::from_exact_iter(key.iter().copied())?;
+    let mut mac = Poly1305::new(&key);
+    mac.update_padded(message);
+    Some(mac.finalize().into())
+}
+
+/// Whether `tag` is the [`mac`] of `message` under `key`, compared in
+/// constant time.
+pub fn verify_mac(key: &[u8], message: &[u8], tag: &[u8]) -> bool {
+    let (Some(key), Ok(tag)) = (
+        poly1305::Key::from_exact_iter(key.iter().copied()),
+        <[u8; 16]>::try_from(tag),
+    ) else {
+        return false;
+    };
+    let mut mac = Poly1305::new(&key);
+    mac.update_padded(message);
+    mac.verify(&tag.into()).is_ok()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::xor;
+    use super::{mac, shamir_interpolate, shamir_split, verify_mac, xor, xor_split, MAC_KEY_LEN};
 
     #[test]
     fn xor_roundtrip_with_same_mask_recovers_original_data() {
@@ -23,4 +157,62 @@ mod tests {
         let out = xor(vec![1, 2, 3, 4], vec![9, 8]);
         assert_eq!(out, vec![8, 10]);
     }
+
+    #[test]
+    fn xor_split_shares_combine_to_secret() {
+        let secret = b"end-to-end-key".to_vec();
+        let shares = xor_split(&secret, 3);
+
+        assert_eq!(shares.len(), 3);
+        assert!(shares.iter().all(|s| s.len() == secret.len()));
+        assert!(shares.iter().all(|s| s != &secret));
+
+        let combined = shares.into_iter().reduce(xor).expect("at least one share");
+        assert_eq!(combined, secret);
+    }
+
+    #[test]
+    fn xor_split_single_share_is_the_secret() {
+        assert_eq!(xor_split(b"key", 1), vec![b"key".to_vec()]);
+    }
+
+    #[test]
+    fn shamir_any_k_shares_rebuild_the_secret() {
+        let secret = b"end-to-end-key".to_vec();
+        let shares = shamir_split(&secret, 2, 3);
+        assert_eq!(shares.len(), 3);
+
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            let points = vec![
+                (a as u8 + 1, shares[a].clone()),
+                (b as u8 + 1, shares[b].clone()),
+            ];
+            assert_eq!(shamir_interpolate(&points, 0), secret);
+        }
+    }
+
+    #[test]
+    fn shamir_interpolation_predicts_remaining_shares() {
+        let secret = b"key".to_vec();
+        let shares = shamir_split(&secret, 3, 5);
+        let points: Vec<(u8, Vec<u8>)> = shares
+            .iter()
+            .take(3)
+            .enumerate()
+            .map(|(i, s)| (i as u8 + 1, s.clone()))
+            .collect();
+
+        assert_eq!(shamir_interpolate(&points, 4), shares[3]);
+        assert_eq!(shamir_interpolate(&points, 5), shares[4]);
+    }
+
+    #[test]
+    fn mac_detects_flipped_bits() {
+        let key = [7u8; MAC_KEY_LEN];
+        let tag = mac(&key, b"masked share").expect("tag");
+        assert!(verify_mac(&key, b"masked share", &tag));
+        assert!(!verify_mac(&key, b"masked shard", &tag));
+        assert!(!verify_mac(&[8u8; MAC_KEY_LEN], b"masked share", &tag));
+        assert_eq!(mac(&key[..16], b"masked share"), None);
+    }
 }